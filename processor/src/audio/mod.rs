@@ -1,17 +1,72 @@
-pub mod extract;
 pub mod shazam;
 
-use anyhow::Result;
-use std::path::Path;
+use std::fmt;
+use symphonia::core::errors::Error as SymphoniaError;
 
-pub async fn process_audio(video_path: &Path) -> Result<Vec<shazam::AudioHash>> {
-    let temp_dir = tempfile::tempdir()?;
-    let temp_path = temp_dir.path();
+/// Why a video yielded no audio fingerprint. None of these fail the job: the
+/// video is still matched on its frames and the cause is reported as a
+/// warning.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioFingerprintError {
+    /// Too little audio to pair two spectral peaks.
+    TooShort { samples: usize },
+    /// No spectral peak stood out anywhere in the clip.
+    Silent,
+    /// The file has no track symphonia can decode.
+    UnsupportedCodec(String),
+    /// Decoding stopped on a packet that could not be skipped, or no packet
+    /// decoded at all.
+    CorruptPacket(String),
+    /// The file could not be read.
+    Io(String),
+}
+
+impl AudioFingerprintError {
+    /// Machine-readable name reported with warnings.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AudioFingerprintError::TooShort { .. } => "too_short",
+            AudioFingerprintError::Silent => "silent",
+            AudioFingerprintError::UnsupportedCodec(_) => "unsupported_codec",
+            AudioFingerprintError::CorruptPacket(_) => "corrupt_packet",
+            AudioFingerprintError::Io(_) => "io",
+        }
+    }
+}
+
+impl fmt::Display for AudioFingerprintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioFingerprintError::TooShort { samples } => {
+                write!(f, "audio too short to fingerprint ({} samples)", samples)
+            }
+            AudioFingerprintError::Silent => write!(f, "audio is silent"),
+            AudioFingerprintError::UnsupportedCodec(e) => write!(f, "unsupported audio: {}", e),
+            AudioFingerprintError::CorruptPacket(e) => write!(f, "corrupt audio: {}", e),
+            AudioFingerprintError::Io(e) => write!(f, "failed to read audio: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AudioFingerprintError {}
 
-    let audio_path = extract::extract_audio(video_path, temp_path).await?;
-    tracing::info!("Extracted audio to {:?}", audio_path);
-    let hashes = shazam::compute_audio_fingerprints(&audio_path)?;
+impl From<SymphoniaError> for AudioFingerprintError {
+    fn from(e: SymphoniaError) -> Self {
+        match e {
+            SymphoniaError::IoError(e) => AudioFingerprintError::Io(e.to_string()),
+            SymphoniaError::Unsupported(what) => {
+                AudioFingerprintError::UnsupportedCodec(what.to_string())
+            }
+            e => AudioFingerprintError::CorruptPacket(e.to_string()),
+        }
+    }
+}
 
-    tracing::info!("Generated {} audio hashes", hashes.len());
-    Ok(hashes)
+impl From<std::io::Error> for AudioFingerprintError {
+    fn from(e: std::io::Error) -> Self {
+        AudioFingerprintError::Io(e.to_string())
+    }
 }
+
+#[cfg(test)]
+mod tests;
@@ -1,128 +1,298 @@
-use anyhow::{Context, Result};
+use super::AudioFingerprintError;
 use rustfft::{num_complex::Complex, FftPlanner};
+use shared::audio_hash::AudioHashKey;
+use std::collections::VecDeque;
 use std::fs::File;
 use std::path::Path;
-use symphonia::core::audio::Signal;
+use std::str::FromStr;
+use symphonia::core::audio::SampleBuffer;
 use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
 use symphonia::core::formats::FormatOptions;
 use symphonia::core::io::MediaSourceStream;
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
 
+pub const SAMPLE_RATE: u32 = 44100;
 const WINDOW_SIZE: usize = 4096;
 const HOP_SIZE: usize = 2048;
 const TARGET_ZONE_SIZE: usize = 5;
 const ANCHOR_OFFSET: usize = 1;
 
+/// Version of the audio fingerprint, sent with every hash. Bump it whenever
+/// the window, hop, target zone, peak picking or hash layout change.
+pub const FINGERPRINT_VERSION: u32 = 4;
+
+/// Lowest and one past the highest FFT bin searched for peaks, about 100 Hz
+/// to 5.5 kHz.
+const MIN_BIN: usize = 10;
+const MAX_BIN: usize = 512;
+
+/// Pitch intervals in `AudioHashKey::Ratio` are counted in semitones, wide
+/// enough that a speed change of a few percent rarely crosses a step.
+const STEPS_PER_OCTAVE: f64 = 12.0;
+/// Resolution of the time ratio in `AudioHashKey::Ratio`.
+const TIME_RATIO_STEPS: f64 = 16.0;
+/// Targets per anchor combined into triplets in `AudioMode::Invariant`,
+/// keeping the hash count per anchor at most `n * (n - 1) / 2`.
+const INVARIANT_FAN_OUT: usize = 6;
+/// Length of the low-pass filter run before downsampling; odd, so it is
+/// centered on a sample.
+const LOW_PASS_TAPS: usize = 31;
+
+/// How peaks are turned into hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AudioMode {
+    /// Anchor/target pairs at absolute frequencies and time deltas. Precise,
+    /// but a pitch shift or tempo change alters every hash.
+    #[default]
+    Absolute,
+    /// Anchor and two targets described by their frequency ratios and the
+    /// ratio of their time deltas, which survive sped-up, slowed and
+    /// pitch-shifted copies at the cost of more collisions.
+    Invariant,
+}
+
+impl FromStr for AudioMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "absolute" => Ok(AudioMode::Absolute),
+            "invariant" => Ok(AudioMode::Invariant),
+            other => Err(format!("unknown audio fingerprint mode: {}", other)),
+        }
+    }
+}
+
+/// How spectral peaks are picked and paired. Every processor feeding one
+/// index must use the same settings, or their hashes will not line up.
+#[derive(Debug, Clone)]
+pub struct AudioConfig {
+    pub mode: AudioMode,
+    /// Windows on each side a peak must dominate.
+    pub time_radius: usize,
+    /// Bins on each side a peak must dominate.
+    pub freq_radius: usize,
+    /// How far above the clip's median level, in dB, a peak must rise. Being
+    /// relative to the clip, it ignores overall volume.
+    pub threshold_db: f32,
+    /// Absolute level, in dB of FFT magnitude, below which nothing counts as
+    /// a peak, so near-silence does not produce noise hashes. The default sits
+    /// well above 16-bit quantization noise but low enough that a copy played
+    /// 40 dB quieter keeps its peaks.
+    pub floor_db: f32,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            mode: AudioMode::Absolute,
+            time_radius: 2,
+            freq_radius: 8,
+            threshold_db: 10.0,
+            floor_db: -40.0,
+        }
+    }
+}
+
+/// Seconds covered by one step of `AudioHash::time_offset`.
+pub const SECONDS_PER_OFFSET: f64 = HOP_SIZE as f64 / SAMPLE_RATE as f64;
+
 #[derive(Debug, Clone, serde::Serialize)]
 pub struct AudioHash {
     pub hash: u64,
     pub time_offset: u32,
 }
 
-pub fn compute_audio_fingerprints(audio_path: &Path) -> Result<Vec<AudioHash>> {
-    let src = File::open(audio_path).context("failed to open audio")?;
+/// Decodes the first audio track of any container symphonia understands and
+/// fingerprints it.
+pub fn compute_audio_fingerprints(
+    audio_path: &Path,
+    config: &AudioConfig,
+) -> Result<Vec<AudioHash>, AudioFingerprintError> {
+    let samples = decode_samples(audio_path)?;
+    fingerprint_samples(&samples, config)
+}
+
+/// Decodes the first audio track to mono `f32` at `SAMPLE_RATE`, whatever its
+/// sample format, channel layout and rate. Packets that fail to decode are
+/// skipped.
+fn decode_samples(audio_path: &Path) -> Result<Vec<f32>, AudioFingerprintError> {
+    let src = File::open(audio_path)?;
     let mss = MediaSourceStream::new(Box::new(src), Default::default());
     let hint = Hint::new();
 
-    let probed = symphonia::default::get_probe()
-        .format(
-            &hint,
-            mss,
-            &FormatOptions::default(),
-            &MetadataOptions::default(),
-        )
-        .context("unsupported audio format")?;
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
 
     let mut format = probed.format;
-    let track = format.default_track().context("no track found")?;
-    let mut decoder = symphonia::default::get_codecs()
-        .make(&track.codec_params, &DecoderOptions::default())
-        .context("unsupported codec")?;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| AudioFingerprintError::UnsupportedCodec("no audio track".to_string()))?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
 
     let mut samples: Vec<f32> = Vec::new();
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    let mut rate = SAMPLE_RATE;
+    let mut skipped = 0;
 
     loop {
         let packet = match format.next_packet() {
             Ok(packet) => packet,
-            Err(symphonia::core::errors::Error::IoError(_)) => break,
+            Err(SymphoniaError::IoError(_)) => break,
             Err(e) => return Err(e.into()),
         };
+        if packet.track_id() != track_id {
+            continue;
+        }
 
-        let decoded = decoder.decode(&packet)?; // Convert to mono f32
-        let mut spec = *decoded.spec();
-        let _duration = decoded.capacity();
-
-        use symphonia::core::audio::AudioBufferRef;
-        match decoded {
-            AudioBufferRef::F32(buf) => {
-                for i in 0..buf.frames() {
-                    let s = buf.chan(0)[i];
-                    samples.push(s);
-                }
-            }
-            AudioBufferRef::U8(buf) => {
-                for i in 0..buf.frames() {
-                    let s = (buf.chan(0)[i] as f32 - 128.0) / 128.0;
-                    samples.push(s);
-                }
-            }
-            AudioBufferRef::S16(buf) => {
-                for i in 0..buf.frames() {
-                    let s = (buf.chan(0)[i] as f32) / 32768.0;
-                    samples.push(s);
-                }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(e)) => {
+                tracing::debug!("Skipping undecodable audio packet: {}", e);
+                skipped += 1;
+                continue;
             }
-            _ => { /* Handle other formats if needed */ }
+            Err(e) => return Err(e.into()),
+        };
+        let spec = *decoded.spec();
+        rate = spec.rate;
+
+        // Converts any sample format to interleaved f32 in [-1, 1].
+        let needed = decoded.capacity() * spec.channels.count();
+        if buffer.as_ref().is_some_and(|b| b.capacity() < needed) {
+            buffer = None;
         }
+        let buf = buffer.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+        buf.copy_interleaved_ref(decoded);
+        downmix(buf.samples(), spec.channels.count(), &mut samples);
     }
 
-    let mut planner = FftPlanner::new();
-    let fft = planner.plan_fft_forward(WINDOW_SIZE);
+    if skipped > 0 {
+        if samples.is_empty() {
+            return Err(AudioFingerprintError::CorruptPacket(format!(
+                "none of {} packets decoded",
+                skipped
+            )));
+        }
+        tracing::warn!("Skipped {} undecodable audio packets", skipped);
+    }
 
-    let mut peaks: Vec<(usize, usize)> = Vec::new();
+    Ok(resample(&samples, rate, SAMPLE_RATE))
+}
 
-    let num_windows = (samples.len() - WINDOW_SIZE) / HOP_SIZE;
+/// Averages each interleaved frame of `channels` samples into one.
+pub(super) fn downmix(interleaved: &[f32], channels: usize, out: &mut Vec<f32>) {
+    let channels = channels.max(1);
+    out.extend(
+        interleaved
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+    );
+}
 
-    for w in 0..num_windows {
-        let start = w * HOP_SIZE;
-        let end = start + WINDOW_SIZE;
-        let window = &samples[start..end];
+/// Linearly interpolates `samples` from `from` Hz to `to` Hz. When
+/// downsampling, content above the new Nyquist frequency is filtered out
+/// first so it does not fold back as spurious peaks.
+pub(super) fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || from == 0 || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let filtered;
+    let samples = if from > to {
+        filtered = low_pass(samples, 0.5 * to as f64 / from as f64);
+        &filtered
+    } else {
+        samples
+    };
+    let step = from as f64 / to as f64;
+    let len = (samples.len() as u64 * to as u64 / from as u64) as usize;
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * step;
+            let index = pos as usize;
+            let frac = (pos - index as f64) as f32;
+            let a = samples[index];
+            let b = samples.get(index + 1).copied().unwrap_or(a);
+            a + (b - a) * frac
+        })
+        .collect()
+}
 
-        let mut buffer: Vec<Complex<f32>> =
-            window.iter().map(|&x| Complex { re: x, im: 0.0 }).collect();
+/// Hann-windowed sinc filter passing frequencies below `cutoff`, a fraction
+/// of the sample rate. Samples past either end count as silence.
+fn low_pass(samples: &[f32], cutoff: f64) -> Vec<f32> {
+    let half = (LOW_PASS_TAPS / 2) as isize;
+    let taps: Vec<f64> = (-half..=half)
+        .map(|n| {
+            let n = n as f64;
+            let sinc = if n == 0.0 {
+                2.0 * cutoff
+            } else {
+                (std::f64::consts::TAU * cutoff * n).sin() / (std::f64::consts::PI * n)
+            };
+            let window = 0.5 + 0.5 * (std::f64::consts::PI * n / (half + 1) as f64).cos();
+            sinc * window
+        })
+        .collect();
+    // Unity gain at DC.
+    let gain: f64 = taps.iter().sum();
 
-        fft.process(&mut buffer);
-        // Simple strategy: Divide into bands and find max in each.
-        // Bands: Low (10-20), Mid (20-40), High (40-80), etc. bins?
-        // 44100Hz / 4096 = ~10.7Hz per bin.
-        let bands = vec![(10, 40), (40, 80), (80, 160), (160, 511)];
-
-        for (min_bin, max_bin) in bands {
-            let mut max_mag = 0.0;
-            let mut max_idx = 0;
-
-            for i in min_bin..max_bin {
-                let mag = buffer[i].norm(); // Magnitude
-                if mag > max_mag {
-                    max_mag = mag;
-                    max_idx = i;
-                }
-            }
+    (0..samples.len() as isize)
+        .map(|i| {
+            let sum: f64 = taps
+                .iter()
+                .zip(i - half..)
+                .filter_map(|(tap, j)| {
+                    usize::try_from(j)
+                        .ok()
+                        .and_then(|j| samples.get(j))
+                        .map(|s| tap * *s as f64)
+                })
+                .sum();
+            (sum / gain) as f32
+        })
+        .collect()
+}
 
-            if max_mag > 10.0 {
-                peaks.push((w, max_idx));
-            }
-        }
+/// Fingerprints mono PCM sampled at `SAMPLE_RATE`.
+pub fn fingerprint_samples(
+    samples: &[f32],
+    config: &AudioConfig,
+) -> Result<Vec<AudioHash>, AudioFingerprintError> {
+    let num_windows = samples.len().saturating_sub(WINDOW_SIZE) / HOP_SIZE;
+    if num_windows <= ANCHOR_OFFSET {
+        return Err(AudioFingerprintError::TooShort {
+            samples: samples.len(),
+        });
     }
 
-    let mut hashes = Vec::new();
+    let peaks = find_peaks(samples, num_windows, config);
+    if peaks.is_empty() {
+        return Err(AudioFingerprintError::Silent);
+    }
+
+    Ok(match config.mode {
+        AudioMode::Absolute => pair_hashes(&peaks),
+        AudioMode::Invariant => triplet_hashes(&peaks),
+    })
+}
 
-    for i in 0..peaks.len() {
-        let (t1, f1) = peaks[i];
+/// Pairs each peak with the peaks in its target zone.
+fn pair_hashes(peaks: &[(usize, usize)]) -> Vec<AudioHash> {
+    let mut hashes = Vec::new();
 
-        for j in (i + 1)..peaks.len() {
-            let (t2, f2) = peaks[j];
+    for (i, &(t1, f1)) in peaks.iter().enumerate() {
+        for &(t2, f2) in &peaks[i + 1..] {
             let dt = t2 - t1;
 
             if dt < ANCHOR_OFFSET {
@@ -132,7 +302,12 @@ pub fn compute_audio_fingerprints(audio_path: &Path) -> Result<Vec<AudioHash>> {
                 break;
             }
 
-            let hash = ((f1 as u64) << 23) | ((f2 as u64) << 9) | (dt as u64);
+            let hash = AudioHashKey::Absolute {
+                anchor_bin: f1 as u16,
+                target_bin: f2 as u16,
+                time_delta: dt as u16,
+            }
+            .encode();
 
             hashes.push(AudioHash {
                 hash,
@@ -141,5 +316,179 @@ pub fn compute_audio_fingerprints(audio_path: &Path) -> Result<Vec<AudioHash>> {
         }
     }
 
-    Ok(hashes)
+    hashes
+}
+
+/// Combines each peak with pairs of peaks from its target zone, keeping only
+/// ratios: scaling every frequency or every time delta by the same factor
+/// leaves the hash unchanged.
+fn triplet_hashes(peaks: &[(usize, usize)]) -> Vec<AudioHash> {
+    let interval = |from: usize, to: usize| {
+        ((to as f64 / from as f64).log2() * STEPS_PER_OCTAVE).round() as i16
+    };
+
+    let mut hashes = Vec::new();
+    for (i, &(t1, f1)) in peaks.iter().enumerate() {
+        let targets: Vec<(usize, usize)> = peaks[i + 1..]
+            .iter()
+            .copied()
+            .skip_while(|&(t, _)| t - t1 < ANCHOR_OFFSET)
+            .take_while(|&(t, _)| t - t1 <= TARGET_ZONE_SIZE + ANCHOR_OFFSET)
+            .take(INVARIANT_FAN_OUT)
+            .collect();
+
+        for (j, &(t2, f2)) in targets.iter().enumerate() {
+            for &(t3, f3) in &targets[j + 1..] {
+                if t3 == t2 {
+                    continue;
+                }
+                let time_ratio = (t2 - t1) as f64 / (t3 - t1) as f64;
+                let hash = AudioHashKey::Ratio {
+                    near_interval: interval(f1, f2),
+                    far_interval: interval(f1, f3),
+                    time_ratio: (time_ratio * TIME_RATIO_STEPS).round() as u16,
+                }
+                .encode();
+
+                hashes.push(AudioHash {
+                    hash,
+                    time_offset: t1 as u32,
+                });
+            }
+        }
+    }
+    hashes
+}
+
+/// Log-magnitude (dB) spectrum of each Hann-windowed frame, restricted to
+/// `MIN_BIN..MAX_BIN`. Computed one window at a time, so the whole clip's
+/// spectrum is never held in memory.
+fn spectrogram(samples: &[f32], num_windows: usize) -> impl Iterator<Item = Vec<f32>> + '_ {
+    let mut planner = FftPlanner::new();
+    let fft = planner.plan_fft_forward(WINDOW_SIZE);
+    let hann: Vec<f32> = (0..WINDOW_SIZE)
+        .map(|n| {
+            let phase = std::f32::consts::TAU * n as f32 / (WINDOW_SIZE - 1) as f32;
+            0.5 - 0.5 * phase.cos()
+        })
+        .collect();
+
+    (0..num_windows).map(move |w| {
+        let start = w * HOP_SIZE;
+        let mut buffer: Vec<Complex<f32>> = samples[start..start + WINDOW_SIZE]
+            .iter()
+            .zip(&hann)
+            .map(|(&x, &h)| Complex { re: x * h, im: 0.0 })
+            .collect();
+        fft.process(&mut buffer);
+        buffer[MIN_BIN..MAX_BIN]
+            .iter()
+            .map(|c| 20.0 * (c.norm() + 1e-6).log10())
+            .collect()
+    })
+}
+
+/// Lowest level, in dB, of the histogram the median is located with. The
+/// quietest bin `spectrogram` can produce is -120 dB.
+const LEVEL_MIN_DB: f32 = -120.0;
+/// Width, in dB, of a histogram bucket.
+const LEVEL_STEP_DB: f32 = 0.01;
+/// Histogram buckets, covering -120 dB to 140 dB, well above a full-scale
+/// sine. Levels outside land in the first or last bucket.
+const LEVEL_BUCKETS: usize = 26_000;
+
+fn level_bucket(level: f32) -> usize {
+    (((level - LEVEL_MIN_DB) / LEVEL_STEP_DB).max(0.0) as usize).min(LEVEL_BUCKETS - 1)
+}
+
+/// `(window, bin)` of every point that is the loudest within `time_radius`
+/// windows and `freq_radius` bins and rises above both the clip's adaptive
+/// threshold and the absolute floor, ordered by window.
+///
+/// The spectrum is computed twice and only the windows around the one being
+/// searched are kept. The first pass counts levels into a histogram to find
+/// the bucket holding the median. The second collects the levels in that
+/// bucket, which give the exact median, and every local maximum that could
+/// clear the threshold, which are then held against it.
+fn find_peaks(samples: &[f32], num_windows: usize, config: &AudioConfig) -> Vec<(usize, usize)> {
+    let mut histogram = vec![0usize; LEVEL_BUCKETS];
+    for row in spectrogram(samples, num_windows) {
+        for level in row {
+            histogram[level_bucket(level)] += 1;
+        }
+    }
+    let mut rank = histogram.iter().sum::<usize>() / 2;
+    let mut median_bucket = 0;
+    for (bucket, &count) in histogram.iter().enumerate() {
+        if rank < count {
+            median_bucket = bucket;
+            break;
+        }
+        rank -= count;
+    }
+    // Lower than any level in the median's bucket, allowing for rounding.
+    let median_floor = match median_bucket {
+        0 => f32::NEG_INFINITY,
+        bucket => LEVEL_MIN_DB + (bucket - 1) as f32 * LEVEL_STEP_DB,
+    };
+    let loose_threshold = (median_floor + config.threshold_db).max(config.floor_db);
+
+    // The neighbourhood maximum is separable: take it along frequency as each
+    // window is computed, then along time over the windows kept.
+    let mut rows = spectrogram(samples, num_windows).map(|row| {
+        let freq_max = sliding_max(&row, config.freq_radius);
+        (row, freq_max)
+    });
+    let mut kept: VecDeque<(Vec<f32>, Vec<f32>)> = VecDeque::new();
+    let mut first_kept = 0;
+
+    let mut median_levels = Vec::new();
+    let mut candidates = Vec::new();
+    for t in 0..num_windows {
+        let from = t.saturating_sub(config.time_radius);
+        let to = (t + config.time_radius + 1).min(num_windows);
+        while first_kept + kept.len() < to {
+            kept.extend(rows.next());
+        }
+        while first_kept < from {
+            kept.pop_front();
+            first_kept += 1;
+        }
+
+        let (row, _) = &kept[t - first_kept];
+        for (f, &level) in row.iter().enumerate() {
+            if level_bucket(level) == median_bucket {
+                median_levels.push(level);
+            }
+            if level <= loose_threshold {
+                continue;
+            }
+            let local_max = kept
+                .iter()
+                .map(|(_, freq_max)| freq_max[f])
+                .fold(f32::MIN, f32::max);
+            if level >= local_max {
+                candidates.push((t, f + MIN_BIN, level));
+            }
+        }
+    }
+
+    let (_, median, _) = median_levels.select_nth_unstable_by(rank, f32::total_cmp);
+    let threshold = (*median + config.threshold_db).max(config.floor_db);
+    candidates
+        .into_iter()
+        .filter(|&(_, _, level)| level > threshold)
+        .map(|(t, f, _)| (t, f))
+        .collect()
+}
+
+/// Maximum of `values` within `radius` of each index.
+fn sliding_max(values: &[f32], radius: usize) -> Vec<f32> {
+    (0..values.len())
+        .map(|i| {
+            let from = i.saturating_sub(radius);
+            let to = (i + radius + 1).min(values.len());
+            values[from..to].iter().copied().fold(f32::MIN, f32::max)
+        })
+        .collect()
 }
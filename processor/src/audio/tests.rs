@@ -0,0 +1,150 @@
+use super::shazam::{downmix, fingerprint_samples, resample, AudioConfig, AudioMode, SAMPLE_RATE};
+use super::*;
+use std::collections::HashSet;
+
+#[test]
+fn test_short_clip_is_reported() {
+    let samples = vec![0.5; 1000];
+    assert_eq!(
+        fingerprint_samples(&samples, &AudioConfig::default()).unwrap_err(),
+        AudioFingerprintError::TooShort { samples: 1000 }
+    );
+}
+
+#[test]
+fn test_silence_is_reported() {
+    let samples = vec![0.0; SAMPLE_RATE as usize * 2];
+    assert_eq!(
+        fingerprint_samples(&samples, &AudioConfig::default()).unwrap_err(),
+        AudioFingerprintError::Silent
+    );
+}
+
+#[test]
+fn test_tone_is_fingerprinted() {
+    let samples: Vec<f32> = (0..SAMPLE_RATE as usize * 2)
+        .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / SAMPLE_RATE as f32).sin())
+        .collect();
+    let hashes = fingerprint_samples(&samples, &AudioConfig::default()).unwrap();
+    assert!(!hashes.is_empty(), "No audio hashes generated");
+}
+
+fn sine(freq: f32, rate: u32, seconds: f32) -> Vec<f32> {
+    (0..(rate as f32 * seconds) as usize)
+        .map(|i| (i as f32 * freq * std::f32::consts::TAU / rate as f32).sin())
+        .collect()
+}
+
+fn rms(samples: &[f32]) -> f32 {
+    (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+#[test]
+fn test_downmix_averages_channels() {
+    let mut mono = Vec::new();
+    downmix(&[1.0, 3.0, -1.0, 1.0], 2, &mut mono);
+    assert_eq!(mono, vec![2.0, 0.0]);
+
+    // Appends, and ignores a trailing partial frame.
+    downmix(&[0.25, 0.5, 0.75, 1.0, 1.0], 3, &mut mono);
+    assert_eq!(mono, vec![2.0, 0.0, 0.5]);
+}
+
+#[test]
+fn test_resample_keeps_duration_and_pitch() {
+    let resampled = resample(&sine(1000.0, 48_000, 1.0), 48_000, SAMPLE_RATE);
+    assert_eq!(resampled.len(), SAMPLE_RATE as usize);
+    // A 1 kHz tone crosses zero twice per cycle at any sample rate.
+    let crossings = resampled
+        .windows(2)
+        .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+        .count();
+    assert!(
+        (1990..=2010).contains(&crossings),
+        "{} crossings",
+        crossings
+    );
+    assert!(rms(&resampled) > 0.65, "Passband tone attenuated");
+
+    let upsampled = resample(&sine(1000.0, 22_050, 1.0), 22_050, SAMPLE_RATE);
+    assert_eq!(upsampled.len(), SAMPLE_RATE as usize);
+}
+
+#[test]
+fn test_resample_filters_above_nyquist() {
+    // 30 kHz survives at 96 kHz but not at 44.1 kHz, where it would alias to
+    // 14.1 kHz.
+    let resampled = resample(&sine(30_000.0, 96_000, 1.0), 96_000, SAMPLE_RATE);
+    assert!(rms(&resampled) < 0.05, "rms {}", rms(&resampled));
+}
+
+/// Eight decaying two-note chords a second, sped up (and so raised in
+/// pitch) by `speed`.
+fn melody(speed: f32) -> Vec<f32> {
+    let melody = [
+        880.0, 1320.0, 1100.0, 1760.0, 990.0, 1480.0, 1175.0, 1980.0, 1046.0, 1568.0,
+    ];
+    let harmony = [
+        660.0, 990.0, 825.0, 1320.0, 742.0, 1110.0, 880.0, 1485.0, 784.0, 1175.0,
+    ];
+    let note_len = (SAMPLE_RATE as f32 / 8.0 / speed) as usize;
+    (0..note_len * 40)
+        .map(|i| {
+            let note = i / note_len;
+            let since_onset = (i % note_len) as f32 / SAMPLE_RATE as f32;
+            let t = i as f32 / SAMPLE_RATE as f32 * speed * std::f32::consts::TAU;
+            let chord = (t * melody[note % 10]).sin() + (t * harmony[note * 3 % 10]).sin();
+            0.5 * chord * (-8.0 * since_onset).exp()
+        })
+        .collect()
+}
+
+/// Hashes of the 2% faster copy also found in the original.
+fn survivors(mode: AudioMode) -> (usize, usize) {
+    let config = AudioConfig {
+        mode,
+        ..AudioConfig::default()
+    };
+    let original = fingerprint_samples(&melody(1.0), &config).unwrap();
+    let faster = fingerprint_samples(&melody(1.02), &config).unwrap();
+    let known: HashSet<u64> = original.iter().map(|h| h.hash).collect();
+    let shared = faster.iter().filter(|h| known.contains(&h.hash)).count();
+    (shared, faster.len())
+}
+
+#[test]
+fn test_invariant_mode_survives_speed_up() {
+    let (absolute, _) = survivors(AudioMode::Absolute);
+    let (shared, total) = survivors(AudioMode::Invariant);
+    assert!(
+        shared * 4 >= total && shared > absolute,
+        "Only {} of {} hashes survived ({} in absolute mode)",
+        shared,
+        total,
+        absolute
+    );
+}
+
+#[test]
+fn test_hashes_ignore_volume() {
+    let config = AudioConfig::default();
+    let loud = melody(1.0);
+    let quiet: Vec<f32> = loud.iter().map(|s| s * 0.01).collect();
+
+    let hashes = |samples: &[f32]| -> HashSet<(u64, u32)> {
+        fingerprint_samples(samples, &config)
+            .unwrap()
+            .iter()
+            .map(|h| (h.hash, h.time_offset))
+            .collect()
+    };
+    let (loud, quiet) = (hashes(&loud), hashes(&quiet));
+    let shared = loud.intersection(&quiet).count();
+    assert!(
+        shared * 10 >= loud.len().max(quiet.len()) * 9,
+        "Only {} of {} hashes survived a 40 dB drop ({} produced)",
+        shared,
+        loud.len(),
+        quiet.len()
+    );
+}
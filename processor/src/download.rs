@@ -1,14 +1,14 @@
 use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_s3::{Client, Config};
 use std::env;
-use std::path::PathBuf;
+use tempfile::NamedTempFile;
 use tokio::io::AsyncWriteExt;
+use tokio::process::ChildStdin;
 
 use anyhow::Result;
 
-pub async fn download_video(video_id: &str, r2_key: &str) -> Result<PathBuf> {
-    println!("Starting download for video_id: {}", video_id);
-
+fn r2_client() -> Result<(Client, String)> {
     dotenv::dotenv().ok();
     let access_key = env::var("R2_ACCESS_KEY_ID")?;
     let secret_key = env::var("R2_SECRET_ACCESS_KEY")?;
@@ -24,26 +24,59 @@ pub async fn download_video(video_id: &str, r2_key: &str) -> Result<PathBuf> {
         .credentials_provider(creds)
         .build();
 
-    let client = Client::from_conf(config);
+    Ok((Client::from_conf(config), bucket))
+}
 
-    let mut response = client
+/// Opens the R2 object as a stream without touching the disk.
+pub async fn open_video(r2_key: &str) -> Result<ByteStream> {
+    let (client, bucket) = r2_client()?;
+    let response = client
         .get_object()
         .bucket(&bucket)
         .key(r2_key)
         .send()
         .await?;
+    Ok(response.body)
+}
+
+/// Copies `stream` into `sink`, closing it at the end so the reader sees EOF.
+/// Stops early, without an error, if the reader exits; its exit status says
+/// why. Returns the number of bytes written.
+pub async fn pipe_to(mut stream: ByteStream, mut sink: ChildStdin) -> Result<u64> {
+    let mut total = 0u64;
+
+    while let Some(bytes) = stream.try_next().await? {
+        if let Err(e) = sink.write_all(&bytes).await {
+            tracing::warn!("Reader stopped consuming the video stream: {}", e);
+            return Ok(total);
+        }
+        total += bytes.len() as u64;
+    }
+
+    sink.shutdown().await.ok();
+    Ok(total)
+}
+
+/// Downloads the whole object to a temporary file, deleted when dropped. Only
+/// needed for inputs ffmpeg cannot read from a pipe, such as MP4s with the
+/// index at the end.
+pub async fn download_video(video_id: &str, r2_key: &str) -> Result<NamedTempFile> {
+    tracing::info!("Starting download for video_id: {}", video_id);
 
-    let temp_dir = std::env::temp_dir();
-    let file_path = temp_dir.join(format!("{}.mp4", video_id));
+    let mut body = open_video(r2_key).await?;
 
-    let mut file = tokio::fs::File::create(&file_path).await?;
+    let video = tempfile::Builder::new()
+        .prefix(&format!("{}-", video_id))
+        .suffix(".mp4")
+        .tempfile()?;
+    let mut file = tokio::fs::File::from_std(video.as_file().try_clone()?);
 
-    while let Some(bytes) = response.body.try_next().await? {
+    while let Some(bytes) = body.try_next().await? {
         file.write_all(&bytes).await?;
     }
 
     file.flush().await?;
 
-    println!("Downloaded video to: {:?}", file_path);
-    Ok(file_path)
+    tracing::info!("Downloaded video to: {:?}", video.path());
+    Ok(video)
 }
@@ -0,0 +1,417 @@
+use crate::audio::shazam::SAMPLE_RATE;
+use crate::download;
+use anyhow::{anyhow, Context, Result};
+use aws_sdk_s3::primitives::ByteStream;
+use image::GrayImage;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::unix::pipe;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+/// Default rate for [`SamplingMode::Fps`].
+pub const FRAMES_PER_SECOND: u32 = 1;
+
+/// Every frame is scaled to this size before it leaves ffmpeg, so frames can
+/// be cut out of the raw stream by length. Still larger than any hash grid.
+pub const FRAME_WIDTH: u32 = 128;
+pub const FRAME_HEIGHT: u32 = 128;
+const FRAME_BYTES: usize = (FRAME_WIDTH * FRAME_HEIGHT) as usize;
+
+/// File descriptor ffmpeg writes the PCM track to; stdout carries the frames.
+const AUDIO_FD: i32 = 3;
+
+/// Bytes of PCM read from [`AUDIO_FD`] at a time.
+const PCM_CHUNK_BYTES: usize = 64 * 1024;
+
+/// ffmpeg log lines kept for the error message when it fails.
+const LOG_TAIL_LINES: usize = 5;
+
+/// Which frames are hashed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SamplingMode {
+    /// Evenly spaced frames, this many per second.
+    Fps(f64),
+    /// The first frame plus every frame whose scene-change score (0..1)
+    /// exceeds the threshold. Static footage yields few hashes, fast cuts many.
+    SceneChange(f64),
+    /// Keyframes only. Non-key frames are not even decoded.
+    IFrames,
+}
+
+impl Default for SamplingMode {
+    fn default() -> Self {
+        SamplingMode::Fps(FRAMES_PER_SECOND as f64)
+    }
+}
+
+impl SamplingMode {
+    fn filter(&self, detect_borders: bool) -> String {
+        let select = match self {
+            SamplingMode::Fps(rate) => format!("fps={},", rate),
+            SamplingMode::SceneChange(threshold) => {
+                format!("select='eq(n,0)+gt(scene,{})',", threshold)
+            }
+            SamplingMode::IFrames => String::new(),
+        };
+        // cropdetect keeps the union of the non-black area seen so far, so a
+        // dark scene does not shrink the crop once the picture has been seen.
+        let cropdetect = if detect_borders {
+            "cropdetect=limit=24:round=2,"
+        } else {
+            ""
+        };
+        format!(
+            "{}scale={}:{},{}showinfo",
+            select, FRAME_WIDTH, FRAME_HEIGHT, cropdetect
+        )
+    }
+}
+
+/// Picture area inside black borders, in frame pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Crop {
+    /// Smallest picture still worth cropping to; anything smaller is a mostly
+    /// black frame rather than a letterboxed one.
+    const MIN_SIDE: u32 = FRAME_WIDTH / 4;
+
+    /// Reads the `crop=w:h:x:y` suggestion of a `cropdetect` line. Returns
+    /// `None` when there is nothing to strip or the suggestion is unusable.
+    fn parse(line: &str) -> Option<Self> {
+        let start = line.find(" crop=")? + " crop=".len();
+        let values: Vec<i64> = line[start..]
+            .split_whitespace()
+            .next()?
+            .split(':')
+            .map(|p| p.parse().ok())
+            .collect::<Option<_>>()?;
+        let [width, height, x, y] = values[..] else {
+            return None;
+        };
+        let crop = Self {
+            x: u32::try_from(x).ok()?,
+            y: u32::try_from(y).ok()?,
+            width: u32::try_from(width).ok()?,
+            height: u32::try_from(height).ok()?,
+        };
+        let full = crop.width == FRAME_WIDTH && crop.height == FRAME_HEIGHT;
+        let fits = crop.x + crop.width <= FRAME_WIDTH && crop.y + crop.height <= FRAME_HEIGHT;
+        let large = crop.width >= Self::MIN_SIDE && crop.height >= Self::MIN_SIDE;
+        (!full && fits && large).then_some(crop)
+    }
+}
+
+/// A sampled 8-bit grayscale frame.
+pub struct Frame {
+    pub image: GrayImage,
+    /// Presentation time, from ffmpeg's `showinfo`.
+    pub timestamp_ms: u64,
+    /// Black borders to strip before hashing, when border detection is on and
+    /// found any.
+    pub crop: Option<Crop>,
+}
+
+/// Where ffmpeg reads the video from.
+pub enum Input<'a> {
+    File(&'a Path),
+    /// Piped into ffmpeg's stdin as it arrives.
+    Stream(ByteStream),
+}
+
+pub struct Extracted {
+    /// Mono PCM at `SAMPLE_RATE`. Empty unless audio was requested.
+    pub samples: Vec<f32>,
+}
+
+/// When a frame is shown and what to crop from it, read from its log lines.
+struct FrameInfo {
+    timestamp_ms: u64,
+    /// `cropdetect`'s verdict for this frame, `Some(None)` when there is
+    /// nothing to strip; `None` when it logged nothing, e.g. while it skips
+    /// the first frames.
+    detected: Option<Option<Crop>>,
+}
+
+/// Demuxes and decodes the video once, without touching the disk. Frames
+/// arrive on ffmpeg's stdout as raw `gray` pixels and their timestamps on
+/// stderr, one `showinfo` line each, preceded by a `cropdetect` line when
+/// `detect_borders` is set. Each frame goes to `frames` as soon as it is
+/// complete, so the caller can hash it while later ones are still decoding.
+/// With `with_audio`, the first audio track is downmixed, resampled and
+/// written to [`AUDIO_FD`] as little-endian `f32`. ffmpeg refuses to start
+/// when that track does not exist, so callers retry without audio.
+pub async fn extract(
+    input: Input<'_>,
+    sampling: &SamplingMode,
+    detect_borders: bool,
+    with_audio: bool,
+    frames: mpsc::Sender<Frame>,
+) -> Result<Extracted> {
+    let source = match &input {
+        Input::File(path) => path.as_os_str().to_owned(),
+        Input::Stream(_) => OsString::from("pipe:0"),
+    };
+
+    let mut command = Command::new("ffmpeg");
+    command.arg("-hide_banner").arg("-nostats");
+    if *sampling == SamplingMode::IFrames {
+        command.arg("-skip_frame:v").arg("nokey");
+    }
+    command
+        .arg("-i")
+        .arg(&source)
+        .arg("-map")
+        .arg("0:v:0")
+        .arg("-vf")
+        .arg(sampling.filter(detect_borders))
+        // Emit selected frames as they are instead of padding to a fixed rate.
+        .arg("-vsync")
+        .arg("vfr")
+        .arg("-pix_fmt")
+        .arg("gray")
+        .arg("-f")
+        .arg("rawvideo")
+        .arg("pipe:1");
+
+    let mut audio = None;
+    if with_audio {
+        let (sender, receiver) = pipe::pipe()?;
+        let write_fd = sender.into_blocking_fd()?;
+        attach_as_audio_fd(&mut command, &write_fd);
+        audio = Some((write_fd, receiver));
+        command
+            .arg("-map")
+            .arg("0:a:0")
+            .arg("-ac")
+            .arg("1")
+            .arg("-ar")
+            .arg(SAMPLE_RATE.to_string())
+            .arg("-f")
+            .arg("f32le")
+            .arg(format!("pipe:{}", AUDIO_FD));
+    }
+
+    command
+        .stdin(match input {
+            Input::File(_) => Stdio::null(),
+            Input::Stream(_) => Stdio::piped(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let mut child = command.spawn().context("Failed to execute ffmpeg")?;
+    // Only ffmpeg may hold the write end, or the read below never sees EOF.
+    let audio = audio.map(|(write_fd, receiver)| {
+        drop(write_fd);
+        receiver
+    });
+    let stdin = child.stdin.take();
+    let stdout = child.stdout.take().context("ffmpeg stdout not captured")?;
+    let stderr = child.stderr.take().context("ffmpeg stderr not captured")?;
+
+    // Feeding stdin, draining both outputs and waiting all have to make
+    // progress together, or ffmpeg blocks on a full pipe.
+    let feed = async {
+        match (input, stdin) {
+            (Input::Stream(stream), Some(sink)) => download::pipe_to(stream, sink).await.map(Some),
+            _ => Ok(None),
+        }
+    };
+    // Timestamps are parsed from stderr as it arrives and matched to frames
+    // by position.
+    let (info_sender, mut info_receiver) = mpsc::unbounded_channel::<FrameInfo>();
+    // Owns stdout, so returning early closes the pipe and ffmpeg stops
+    // instead of blocking on it.
+    let read_frames = async move {
+        let mut stdout = stdout;
+        // Frames that arrive before cropdetect's first verdict wait for it,
+        // so they are not stored uncropped under a `-crop` tag.
+        let mut known = None;
+        let mut pending = Vec::new();
+        loop {
+            let mut pixels = vec![0; FRAME_BYTES];
+            match stdout.read_exact(&mut pixels).await {
+                Ok(_) => {}
+                // A truncated last frame is dropped.
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let Some(info) = info_receiver.recv().await else {
+                return Err(anyhow!("ffmpeg reported fewer timestamps than frames"));
+            };
+            let image = GrayImage::from_raw(FRAME_WIDTH, FRAME_HEIGHT, pixels)
+                .expect("chunk length matches frame size");
+            known = info.detected.or(known);
+            pending.push((image, info.timestamp_ms));
+            if detect_borders && known.is_none() {
+                continue;
+            }
+            send_pending(&frames, &mut pending, known.flatten()).await?;
+        }
+        // A clip too short for cropdetect to judge is hashed as it is.
+        send_pending(&frames, &mut pending, None).await?;
+        if info_receiver.recv().await.is_some() {
+            return Err(anyhow!("ffmpeg reported more timestamps than frames"));
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    // Samples are decoded chunk by chunk, so the raw bytes are never held in
+    // full next to them.
+    let read_audio = async move {
+        let mut samples = Vec::new();
+        if let Some(mut receiver) = audio {
+            let mut chunk = vec![0u8; PCM_CHUNK_BYTES];
+            let mut filled = 0;
+            loop {
+                let read = receiver.read(&mut chunk[filled..]).await?;
+                if read == 0 {
+                    break;
+                }
+                filled += read;
+                // A sample may straddle two reads; its first bytes carry over.
+                let whole = filled - filled % 4;
+                samples.extend(
+                    chunk[..whole]
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+                );
+                chunk.copy_within(whole..filled, 0);
+                filled -= whole;
+            }
+        }
+        Ok::<_, std::io::Error>(samples)
+    };
+    let read_log = async move {
+        let mut crop = None;
+        let mut tail = VecDeque::with_capacity(LOG_TAIL_LINES);
+        let mut stderr = BufReader::new(stderr);
+        let mut raw = Vec::new();
+        loop {
+            raw.clear();
+            if stderr.read_until(b'\n', &mut raw).await? == 0 {
+                break;
+            }
+            // Metadata and file names echoed by ffmpeg need not be UTF-8.
+            let line = String::from_utf8_lossy(&raw).trim_end().to_string();
+            if let Some(pts_time) = parse_pts_time(&line) {
+                let timestamp_ms = to_ms(pts_time);
+                // cropdetect may skip the first frames, so a verdict only
+                // applies to the frame shown at the time it names.
+                let detected = crop
+                    .take()
+                    .filter(|(t, _)| *t == timestamp_ms)
+                    .map(|(_, c)| c);
+                info_sender
+                    .send(FrameInfo {
+                        timestamp_ms,
+                        detected,
+                    })
+                    .ok();
+                continue;
+            }
+            if line.contains("Parsed_cropdetect") {
+                if let Some(t) = parse_field(&line, " t:") {
+                    crop = Some((to_ms(t), Crop::parse(&line)));
+                }
+                continue;
+            }
+            tracing::debug!("ffmpeg: {}", line);
+            if tail.len() == LOG_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        Ok::<_, std::io::Error>(tail)
+    };
+    let (fed, read, samples, log, status) =
+        tokio::join!(feed, read_frames, read_audio, read_log, child.wait());
+
+    if let Some(bytes) = fed? {
+        tracing::info!("Streamed {} bytes into ffmpeg", bytes);
+    }
+    let tail = log?;
+    let status = status.context("Failed to execute ffmpeg")?;
+    if !status.success() {
+        let tail: Vec<String> = tail.into();
+        return Err(anyhow!(
+            "FFmpeg exited with non-zero status: {}",
+            tail.join(" | ")
+        ));
+    }
+
+    read?;
+    Ok(Extracted { samples: samples? })
+}
+
+/// Sends the frames waiting in `pending`, in order, with `crop`.
+async fn send_pending(
+    frames: &mpsc::Sender<Frame>,
+    pending: &mut Vec<(GrayImage, u64)>,
+    crop: Option<Crop>,
+) -> Result<()> {
+    for (image, timestamp_ms) in pending.drain(..) {
+        let frame = Frame {
+            image,
+            timestamp_ms,
+            crop,
+        };
+        if frames.send(frame).await.is_err() {
+            return Err(anyhow!("Frame consumer stopped"));
+        }
+    }
+    Ok(())
+}
+
+/// Reads `pts_time` from a `showinfo` frame line such as
+/// `[Parsed_showinfo_2 @ 0x..] n:   3 pts:  3072 pts_time:3 duration: ...`.
+fn parse_pts_time(line: &str) -> Option<f64> {
+    if !line.contains("Parsed_showinfo") {
+        return None;
+    }
+    parse_field(line, " pts_time:")
+}
+
+/// Parses the number following `key` in an ffmpeg log line.
+fn parse_field(line: &str, key: &str) -> Option<f64> {
+    let rest = &line[line.find(key)? + key.len()..];
+    rest.split_whitespace().next()?.parse().ok()
+}
+
+fn to_ms(seconds: f64) -> u64 {
+    (seconds * 1000.0).round().max(0.0) as u64
+}
+
+/// Makes `write_fd` the child's [`AUDIO_FD`].
+fn attach_as_audio_fd(command: &mut Command, write_fd: &OwnedFd) {
+    let fd = write_fd.as_raw_fd();
+    // SAFETY: only async-signal-safe calls between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            if fd == AUDIO_FD {
+                // dup2 onto itself keeps close-on-exec set, so clear it here.
+                if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            } else if libc::dup2(fd, AUDIO_FD) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests;
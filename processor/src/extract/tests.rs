@@ -0,0 +1,85 @@
+use super::*;
+
+#[test]
+fn test_parse_pts_time() {
+    let cases = [
+        (
+            "[Parsed_showinfo_2 @ 0x55d0c8b4b2c0] n:   0 pts:      0 pts_time:0       \
+             duration:   1024 duration_time:0.04    fmt:gray sar:1/1 s:128x128 i:P iskey:1 \
+             type:I checksum:5E4A4D7B plane_checksum:[5E4A4D7B] mean:[16] stdev:[0.0]",
+            Some(0.0),
+        ),
+        (
+            "[Parsed_showinfo_3 @ 0x7f3a1c004a80] n:  12 pts: 153600 pts_time:12.04   \
+             pos:  1234567 fmt:gray sar:1/1 s:128x128 i:P iskey:0 type:P \
+             checksum:0B8E3F21 plane_checksum:[0B8E3F21] mean:[94] stdev:[41.2]",
+            Some(12.04),
+        ),
+        (
+            "[Parsed_showinfo_2 @ 0x55d0c8b4b2c0] config in time_base: 1/12800, frame_rate: 25/1",
+            None,
+        ),
+        (
+            "[Parsed_showinfo_2 @ 0x55d0c8b4b2c0]   side data - display matrix: rotation of -90.00 degrees",
+            None,
+        ),
+        (
+            "[Parsed_cropdetect_2 @ 0x55f1c2a3b4c0] x1:0 x2:127 y1:16 y2:111 w:128 h:96 x:0 y:16 \
+             pts:2048 t:0.080000 limit:0.094118 crop=128:96:0:16",
+            None,
+        ),
+        ("  Duration: 00:00:05.00, start: 0.000000, bitrate: 112 kb/s", None),
+    ];
+    for (line, expected) in cases {
+        assert_eq!(parse_pts_time(line), expected, "{}", line);
+    }
+}
+
+#[test]
+fn test_crop_parse() {
+    let line = |crop: &str| {
+        format!(
+            "[Parsed_cropdetect_2 @ 0x55f1c2a3b4c0] x1:0 x2:127 y1:16 y2:111 w:128 h:96 \
+             x:0 y:16 pts:2048 t:0.080000 limit:0.094118 crop={}",
+            crop
+        )
+    };
+    let cases = [
+        // Letterboxed.
+        (
+            line("128:96:0:16"),
+            Some(Crop {
+                x: 0,
+                y: 16,
+                width: 128,
+                height: 96,
+            }),
+        ),
+        // Pillarboxed.
+        (
+            line("96:128:16:0"),
+            Some(Crop {
+                x: 16,
+                y: 0,
+                width: 96,
+                height: 128,
+            }),
+        ),
+        // No borders.
+        (line("128:128:0:0"), None),
+        // An all-black frame.
+        (line("-126:-126:128:128"), None),
+        // Too small to be the picture.
+        (line("16:16:56:56"), None),
+        // Reaches past the frame.
+        (line("128:96:8:16"), None),
+        (line("128:96"), None),
+        (
+            "[Parsed_showinfo_3 @ 0x7f3a1c004a80] n:  12 pts: 153600 pts_time:12.04".to_string(),
+            None,
+        ),
+    ];
+    for (line, expected) in cases {
+        assert_eq!(Crop::parse(&line), expected, "{}", line);
+    }
+}
@@ -1,16 +1,104 @@
-use anyhow::{Context, Result};
-use image::io::Reader as ImageReader;
-use img_hash::{HasherConfig, ImageHash};
-use std::path::Path;
+use image::{imageops, GrayImage};
+use img_hash::{HashAlg, Hasher, HasherConfig, ImageHash};
+use std::fmt;
+use std::str::FromStr;
 
-pub fn compute_phash(image_path: &Path) -> Result<ImageHash> {
-    let image = ImageReader::open(image_path)
-        .context(format!("Failed to open image: {:?}", image_path))?
-        .decode()
-        .context("Failed to decode image")?;
+/// Perceptual hash computed for each frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// Mean of the low DCT frequencies, as the README describes.
+    PHash,
+    /// Sign of the horizontal gradient. `img_hash`'s default, and what every
+    /// hash indexed before this setting existed was computed with.
+    DHash,
+    /// Each pixel against the mean brightness.
+    AHash,
+    /// Mean brightness per block, compared with the median block.
+    Blockhash,
+}
+
+impl HashAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgorithm::PHash => "phash",
+            HashAlgorithm::DHash => "dhash",
+            HashAlgorithm::AHash => "ahash",
+            HashAlgorithm::Blockhash => "blockhash",
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "phash" => Ok(HashAlgorithm::PHash),
+            "dhash" => Ok(HashAlgorithm::DHash),
+            "ahash" => Ok(HashAlgorithm::AHash),
+            "blockhash" => Ok(HashAlgorithm::Blockhash),
+            other => Err(format!("unknown hash algorithm: {}", other)),
+        }
+    }
+}
 
-    let hasher = HasherConfig::new().to_hasher();
-    let hash = hasher.hash_image(&image);
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashConfig {
+    pub algorithm: HashAlgorithm,
+    /// Hash grid side; a hash has `size * size` bits. Kept a multiple of 4 so
+    /// hashes split evenly into the worker's 16-bit LSH bands.
+    pub size: u32,
+}
+
+impl Default for HashConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: HashAlgorithm::DHash,
+            size: 8,
+        }
+    }
+}
+
+impl HashConfig {
+    /// Algorithm and size, e.g. `dhash-8`.
+    pub fn tag(&self) -> String {
+        format!("{}-{}", self.algorithm, self.size)
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        let config = HasherConfig::new().hash_size(self.size, self.size);
+        match self.algorithm {
+            HashAlgorithm::PHash => config.hash_alg(HashAlg::Mean).preproc_dct(),
+            HashAlgorithm::DHash => config.hash_alg(HashAlg::Gradient),
+            HashAlgorithm::AHash => config.hash_alg(HashAlg::Mean),
+            HashAlgorithm::Blockhash => config.hash_alg(HashAlg::Blockhash),
+        }
+        .to_hasher()
+    }
+}
+
+pub fn compute_hash(hasher: &Hasher, frame: &GrayImage) -> ImageHash {
+    hasher.hash_image(frame)
+}
 
-    Ok(hash)
+/// Hashes the `width` x `height` area of `frame` whose top-left corner is at
+/// (`x`, `y`): the picture inside black borders, or one region of the frame.
+pub fn compute_hash_in(
+    hasher: &Hasher,
+    frame: &GrayImage,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> ImageHash {
+    compute_hash(
+        hasher,
+        &imageops::crop_imm(frame, x, y, width, height).to_image(),
+    )
 }
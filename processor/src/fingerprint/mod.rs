@@ -1,26 +1,192 @@
-pub mod extract;
 pub mod hash;
 
+use crate::extract::{self, Frame, Input, SamplingMode};
 use anyhow::Result;
-use img_hash::ImageHash;
+use hash::HashConfig;
+use image::{imageops, GenericImageView, GrayImage};
+use img_hash::{Hasher, ImageHash};
+use std::borrow::Cow;
 use std::path::Path;
+use tokio::sync::mpsc;
 
-pub async fn process_video(video_path: &Path) -> Result<Vec<String>> {
-    let temp_dir = tempfile::tempdir()?;
-    let temp_path = temp_dir.path();
+/// Extracted frames allowed to wait for the hashing thread.
+const FRAME_QUEUE: usize = 64;
 
-    let frames = extract::extract_frames(video_path, temp_path).await?;
-    tracing::info!("Extracted {} frames", frames.len());
+/// Standard deviation, in grey levels, below which a region has no detail to
+/// hash. Solid borders and backgrounds all hash alike, and like a black frame.
+const MIN_REGION_STDDEV: f64 = 8.0;
 
-    let mut hashes = Vec::new();
-    for frame in frames {
-        let hash = hash::compute_phash(&frame)?;
-        let bytes = hash.as_bytes();
-        let hex_str: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
-        hashes.push(hex_str);
+/// Version of the frame extraction and hashing code, sent with every hash.
+/// Bump it whenever a change makes new hashes incomparable with stored ones;
+/// the Upload API then only compares like with like, and its backfill
+/// re-fingerprints older videos. Version 1 hashed full-size JPEG frames.
+pub const FINGERPRINT_VERSION: u32 = 2;
+
+/// How frames are picked and hashed.
+#[derive(Debug, Clone, Default)]
+pub struct FingerprintConfig {
+    pub sampling: SamplingMode,
+    pub hash: HashConfig,
+    /// Strip black borders (letterboxing, pillarboxing) before hashing.
+    pub crop_borders: bool,
+    /// Also hash each frame flipped horizontally, so mirrored copies of
+    /// indexed videos are recognised.
+    pub mirrored: bool,
+    /// Also hash each cell of a `region_grid` x `region_grid` grid and the
+    /// center of the frame, so a video shrunk into a corner or framed by
+    /// decorations is recognised. `0` turns it off.
+    pub region_grid: u32,
+}
+
+impl FingerprintConfig {
+    /// Stored next to every hash; only hashes with equal tags are compared.
+    /// Cropping changes the hashes of letterboxed videos, so it is part of
+    /// the tag. Mirrored and region hashes are only used for lookups and are
+    /// not.
+    pub fn tag(&self) -> String {
+        if self.crop_borders {
+            format!("{}-crop", self.hash.tag())
+        } else {
+            self.hash.tag()
+        }
     }
+}
+
+/// A frame hash and when the frame is shown.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct FrameHash {
+    pub hash: String,
+    pub timestamp_ms: u64,
+    /// Hash of the horizontally flipped frame, when enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirrored: Option<String>,
+    /// Hashes of the grid cells, row by row, then of the center, when enabled.
+    /// `None` for a region without detail.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<Option<String>>,
+}
 
+/// Extracts frames and hashes them, calling `on_frame(done)` after each frame
+/// is hashed.
+pub async fn process_video(
+    video_path: &Path,
+    config: &FingerprintConfig,
+    on_frame: impl Fn(usize) + Send + 'static,
+) -> Result<Vec<FrameHash>> {
+    let (hashes, _) = extract_and_hash(Input::File(video_path), config, false, on_frame).await?;
+    tracing::info!("Extracted {} frames", hashes.len());
     Ok(hashes)
 }
 
+/// Runs [`extract::extract`] and hashes each frame on a blocking thread as
+/// soon as ffmpeg emits it, so only the frames waiting in [`FRAME_QUEUE`] are
+/// held in memory. Returns the hashes, in order, and the PCM track.
+pub async fn extract_and_hash(
+    input: Input<'_>,
+    config: &FingerprintConfig,
+    with_audio: bool,
+    on_frame: impl Fn(usize) + Send + 'static,
+) -> Result<(Vec<FrameHash>, Vec<f32>)> {
+    let (sender, mut receiver) = mpsc::channel::<Frame>(FRAME_QUEUE);
+    // `Hasher` is not `Send`, so it is built on the thread that uses it.
+    let hashing = {
+        let config = config.clone();
+        tokio::task::spawn_blocking(move || {
+            let hasher = config.hash.hasher();
+            let mut hashes = Vec::new();
+            while let Some(frame) = receiver.blocking_recv() {
+                hashes.push(hash_frame(&hasher, &frame, &config));
+                on_frame(hashes.len());
+            }
+            hashes
+        })
+    };
+
+    let extracted = extract::extract(
+        input,
+        &config.sampling,
+        config.crop_borders,
+        with_audio,
+        sender,
+    )
+    .await;
+    let hashes = hashing.await?;
+    Ok((hashes, extracted?.samples))
+}
+
+/// Hashes one frame with every view `config` asks for.
+fn hash_frame(hasher: &Hasher, frame: &Frame, config: &FingerprintConfig) -> FrameHash {
+    let image = match frame.crop {
+        Some(c) => {
+            Cow::Owned(imageops::crop_imm(&frame.image, c.x, c.y, c.width, c.height).to_image())
+        }
+        None => Cow::Borrowed(&frame.image),
+    };
+    let mirrored = config.mirrored.then(|| {
+        to_hex(&hash::compute_hash(
+            hasher,
+            &imageops::flip_horizontal(&*image),
+        ))
+    });
+    let regions = regions(image.width(), image.height(), config.region_grid)
+        .into_iter()
+        .map(|(x, y, w, h)| {
+            has_detail(&image, x, y, w, h)
+                .then(|| to_hex(&hash::compute_hash_in(hasher, &image, x, y, w, h)))
+        })
+        .collect();
+    FrameHash {
+        hash: to_hex(&hash::compute_hash(hasher, &image)),
+        timestamp_ms: frame.timestamp_ms,
+        mirrored,
+        regions,
+    }
+}
+
+/// `(x, y, width, height)` of each grid cell, row by row, followed by the
+/// central area that leaves a sixth of the frame on every side. Empty when
+/// `grid` is `0`; cells are left out when the frame is too small to give
+/// each of them a pixel.
+fn regions(width: u32, height: u32, grid: u32) -> Vec<(u32, u32, u32, u32)> {
+    if grid == 0 {
+        return Vec::new();
+    }
+    let (cell_w, cell_h) = (width / grid, height / grid);
+    let mut regions: Vec<_> = (0..grid * grid)
+        .map(|i| ((i % grid) * cell_w, (i / grid) * cell_h, cell_w, cell_h))
+        .filter(|&(_, _, w, h)| w > 0 && h > 0)
+        .collect();
+    regions.push((
+        width / 6,
+        height / 6,
+        width - 2 * (width / 6),
+        height - 2 * (height / 6),
+    ));
+    regions
+}
+
+/// Whether the `width` x `height` area at (`x`, `y`) varies enough to be
+/// worth hashing; see [`MIN_REGION_STDDEV`].
+fn has_detail(image: &GrayImage, x: u32, y: u32, width: u32, height: u32) -> bool {
+    let (n, sum, squares) = imageops::crop_imm(image, x, y, width, height)
+        .pixels()
+        .fold((0.0, 0.0, 0.0), |(n, sum, squares), (_, _, p)| {
+            let v = p[0] as f64;
+            (n + 1.0, sum + v, squares + v * v)
+        });
+    if n == 0.0 {
+        return false;
+    }
+    let mean = sum / n;
+    (squares / n - mean * mean).max(0.0).sqrt() >= MIN_REGION_STDDEV
+}
+
+fn to_hex(hash: &ImageHash) -> String {
+    hash.as_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
 mod tests;
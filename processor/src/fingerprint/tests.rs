@@ -1,26 +1,113 @@
-#[cfg(test)]
-mod tests {
-    use crate::fingerprint::process_video;
-    use std::path::PathBuf;
-
-    #[tokio::test]
-    async fn test_process_video() {
-        let video_path = PathBuf::from("test_video.mp4");
-
-        if !video_path.exists() {
-            eprintln!("Skipping test: test_video.mp4 not found. Run 'ffmpeg -f lavfi -i \"smptebars=size=1280x720:duration=5\" -c:v libx264 -pix_fmt yuv420p test_video.mp4' to generate it.");
-            return;
-        }
-
-        let result = process_video(&video_path).await;
-        assert!(
-            result.is_ok(),
-            "Failed to process video: {:?}",
-            result.err()
-        );
-
-        let hashes = result.unwrap();
-        assert!(!hashes.is_empty(), "No fingerprint hashes generated");
-        println!("Generated {} hashes: {:?}", hashes.len(), hashes);
+use super::*;
+use image::{imageops, GrayImage, Luma};
+use std::path::PathBuf;
+
+#[tokio::test]
+async fn test_process_video() {
+    let video_path = PathBuf::from("test_video.mp4");
+
+    if !video_path.exists() {
+        eprintln!("Skipping test: test_video.mp4 not found. Run 'ffmpeg -f lavfi -i \"smptebars=size=1280x720:duration=5\" -c:v libx264 -pix_fmt yuv420p test_video.mp4' to generate it.");
+        return;
     }
+
+    let result = process_video(&video_path, &FingerprintConfig::default(), |_| {}).await;
+    assert!(
+        result.is_ok(),
+        "Failed to process video: {:?}",
+        result.err()
+    );
+
+    let hashes = result.unwrap();
+    assert!(!hashes.is_empty(), "No fingerprint hashes generated");
+    println!("Generated {} hashes: {:?}", hashes.len(), hashes);
+}
+
+#[test]
+fn test_regions() {
+    assert!(regions(128, 128, 0).is_empty());
+    assert_eq!(
+        regions(128, 128, 2),
+        vec![
+            (0, 0, 64, 64),
+            (64, 0, 64, 64),
+            (0, 64, 64, 64),
+            (64, 64, 64, 64),
+            (21, 21, 86, 86),
+        ]
+    );
+    // A cropped, non-square picture.
+    let cells = regions(96, 60, 3);
+    assert_eq!(cells.len(), 10);
+    assert_eq!(cells[4], (32, 20, 32, 20));
+    assert_eq!(cells[9], (16, 10, 64, 40));
+    // Too small for the grid: only the center is left.
+    assert_eq!(regions(3, 3, 4), vec![(0, 0, 3, 3)]);
+}
+
+#[test]
+fn test_compute_hash_in() {
+    let hasher = HashConfig::default().hasher();
+    let picture = GrayImage::from_fn(64, 64, |x, y| Luma([((x * 7 + y * 3) % 256) as u8]));
+    let mut frame = GrayImage::new(128, 128);
+    imageops::replace(&mut frame, &picture, 64, 0);
+
+    let region = hash::compute_hash_in(&hasher, &frame, 64, 0, 64, 64);
+    assert_eq!(region, hash::compute_hash(&hasher, &picture));
+    assert_ne!(region, hash::compute_hash(&hasher, &frame));
+}
+
+#[test]
+fn test_regions_without_detail_are_not_hashed() {
+    // A picture in the top-right cell; the rest of the frame is black.
+    let picture = GrayImage::from_fn(64, 64, |x, y| Luma([((x * 7 + y * 3) % 256) as u8]));
+    let mut image = GrayImage::new(128, 128);
+    imageops::replace(&mut image, &picture, 64, 0);
+    let frame = Frame {
+        image,
+        timestamp_ms: 0,
+        crop: None,
+    };
+    let config = FingerprintConfig {
+        region_grid: 2,
+        ..Default::default()
+    };
+
+    let hashed = hash_frame(&config.hash.hasher(), &frame, &config);
+    assert_eq!(hashed.regions.len(), 5);
+    assert!(hashed.regions[0].is_none());
+    assert!(hashed.regions[1].is_some());
+    assert!(hashed.regions[2].is_none());
+    assert!(hashed.regions[3].is_none());
+    assert!(hashed.regions[4].is_some());
+}
+
+#[test]
+fn test_hash_algorithm_tags() {
+    use hash::HashAlgorithm;
+    for algorithm in [
+        HashAlgorithm::PHash,
+        HashAlgorithm::DHash,
+        HashAlgorithm::AHash,
+        HashAlgorithm::Blockhash,
+    ] {
+        assert_eq!(algorithm.as_str().parse(), Ok(algorithm));
+    }
+    assert!("md5".parse::<HashAlgorithm>().is_err());
+
+    assert_eq!(FingerprintConfig::default().tag(), "dhash-8");
+    let config = FingerprintConfig {
+        hash: HashConfig {
+            algorithm: HashAlgorithm::PHash,
+            size: 16,
+        },
+        crop_borders: true,
+        ..Default::default()
+    };
+    assert_eq!(config.tag(), "phash-16-crop");
+
+    // A hash has `size * size` bits, which the worker scales its tolerance to.
+    let image = GrayImage::from_fn(64, 64, |x, y| Luma([((x * 7 + y * 3) % 256) as u8]));
+    let hash = hash::compute_hash(&config.hash.hasher(), &image);
+    assert_eq!(hash.as_bytes().len() * 8, 256);
 }
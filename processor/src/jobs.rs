@@ -0,0 +1,141 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Finished jobs kept around for `GET /jobs/{id}` before the oldest are dropped.
+const MAX_FINISHED_JOBS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Queued,
+    Downloading,
+    ExtractingFrames,
+    Hashing,
+    Audio,
+    Callback,
+    Completed,
+    Failed,
+}
+
+impl Stage {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Stage::Completed | Stage::Failed)
+    }
+}
+
+/// Something that went wrong without failing the job, such as a video whose
+/// audio could not be fingerprinted.
+#[derive(Debug, Clone, Serialize)]
+pub struct Warning {
+    pub stage: Stage,
+    /// Machine-readable cause, e.g. `too_short`.
+    pub kind: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub video_id: String,
+    pub stage: Stage,
+    /// Overall progress from 0.0 to 1.0.
+    pub progress: f32,
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Warning>,
+    /// The Upload API's answer to the callback, once the job completes.
+    pub result: Option<serde_json::Value>,
+    #[serde(skip)]
+    seq: u64,
+}
+
+/// In-memory record of every job the processor has accepted.
+#[derive(Clone, Default)]
+pub struct JobStore {
+    inner: Arc<RwLock<Jobs>>,
+}
+
+#[derive(Default)]
+struct Jobs {
+    by_id: HashMap<String, JobStatus>,
+    next_seq: u64,
+}
+
+impl JobStore {
+    pub fn create(&self, video_id: &str) -> String {
+        let id = format!("{:016x}", rand::random::<u64>());
+        let mut jobs = self.inner.write().unwrap();
+
+        let finished = jobs
+            .by_id
+            .values()
+            .filter(|j| j.stage.is_finished())
+            .count();
+        if finished > MAX_FINISHED_JOBS {
+            let mut oldest: Vec<(u64, String)> = jobs
+                .by_id
+                .values()
+                .filter(|j| j.stage.is_finished())
+                .map(|j| (j.seq, j.id.clone()))
+                .collect();
+            oldest.sort();
+            for (_, id) in oldest.into_iter().take(finished - MAX_FINISHED_JOBS) {
+                jobs.by_id.remove(&id);
+            }
+        }
+
+        let seq = jobs.next_seq;
+        jobs.next_seq += 1;
+        jobs.by_id.insert(
+            id.clone(),
+            JobStatus {
+                id: id.clone(),
+                video_id: video_id.to_string(),
+                stage: Stage::Queued,
+                progress: 0.0,
+                error: None,
+                warnings: Vec::new(),
+                result: None,
+                seq,
+            },
+        );
+        id
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.inner.write().unwrap().by_id.remove(id);
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobStatus> {
+        self.inner.read().unwrap().by_id.get(id).cloned()
+    }
+
+    pub fn update(&self, id: &str, stage: Stage, progress: f32) {
+        if let Some(job) = self.inner.write().unwrap().by_id.get_mut(id) {
+            job.stage = stage;
+            job.progress = progress.clamp(0.0, 1.0);
+        }
+    }
+
+    pub fn warn(&self, id: &str, warning: Warning) {
+        if let Some(job) = self.inner.write().unwrap().by_id.get_mut(id) {
+            job.warnings.push(warning);
+        }
+    }
+
+    pub fn complete(&self, id: &str, result: serde_json::Value) {
+        if let Some(job) = self.inner.write().unwrap().by_id.get_mut(id) {
+            job.stage = Stage::Completed;
+            job.progress = 1.0;
+            job.result = Some(result);
+        }
+    }
+
+    pub fn fail(&self, id: &str, error: String) {
+        if let Some(job) = self.inner.write().unwrap().by_id.get_mut(id) {
+            job.stage = Stage::Failed;
+            job.error = Some(error);
+        }
+    }
+}
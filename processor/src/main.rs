@@ -1,25 +1,38 @@
 use axum::{
-    extract::{Json, State},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::post,
+    extract::{Json, Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
     Router,
 };
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_json::json;
 use std::net::SocketAddr;
 use tower_http::trace::TraceLayer;
-use tracing_subscriber;
 
 mod audio;
 mod download;
+mod extract;
 mod fingerprint;
+mod jobs;
+mod pipeline;
+
+use jobs::JobStore;
+use pipeline::{Job, Pool, PoolConfig};
 
 #[derive(Debug, Deserialize)]
 struct ProcessRequest {
     video_id: String,
     r2_key: String,
+    /// Backfill of an already indexed video; see `Job::refingerprint`.
+    #[serde(default)]
+    refingerprint: bool,
+}
+
+#[derive(Clone)]
+struct AppState {
+    jobs: JobStore,
+    pool: Pool,
 }
 
 #[tokio::main]
@@ -29,14 +42,26 @@ async fn main() {
         .with_max_level(tracing::Level::INFO)
         .init();
 
-    // Check if API URL is set, for info logging only (we read it in handler)
+    // Check if API URL is set, for info logging only (we read it in the pipeline)
     let api_url =
         std::env::var("UPLOAD_API_URL").unwrap_or_else(|_| "http://127.0.0.1:8787".to_string());
     tracing::info!("Configured to callback Upload API at: {}", api_url);
 
+    let config = PoolConfig::from_env();
+    tracing::info!(
+        "Starting {} workers with a queue depth of {}",
+        config.workers,
+        config.queue_depth
+    );
+    let jobs = JobStore::default();
+    let pool = pipeline::start(config, jobs.clone());
+
     let app = Router::new()
         .route("/process", post(process_video))
-        .layer(TraceLayer::new_for_http());
+        .route("/jobs/{id}", get(job_status))
+        .route("/load", get(load))
+        .layer(TraceLayer::new_for_http())
+        .with_state(AppState { jobs, pool });
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
     tracing::info!("Processor service running on http://{}", addr);
@@ -45,95 +70,56 @@ async fn main() {
         .unwrap();
 }
 
-async fn process_video(Json(payload): Json<ProcessRequest>) -> impl IntoResponse {
+/// Accepts a video for fingerprinting and returns immediately; progress is
+/// reported by `GET /jobs/{id}`. When the queue is full the caller is told to
+/// come back later with `503` and `Retry-After`.
+async fn process_video(
+    State(state): State<AppState>,
+    Json(payload): Json<ProcessRequest>,
+) -> Response {
     tracing::info!("Processing video: {:?}", payload);
 
-    match download::download_video(&payload.video_id, &payload.r2_key).await {
-        Ok(path) => {
-            tracing::info!("Video downloaded to: {:?}", path);
-
-            // Process fingerprints
-            let video_hashes_result = fingerprint::process_video(&path).await;
-
-            // Process Audio
-            let audio_hashes = match audio::process_audio(&path).await {
-                Ok(h) => h,
-                Err(e) => {
-                    tracing::warn!("Audio processing failed: {}", e);
-                    Vec::new()
-                }
-            };
+    let id = state.jobs.create(&payload.video_id);
+    let job = Job {
+        id: id.clone(),
+        video_id: payload.video_id.clone(),
+        r2_key: payload.r2_key,
+        refingerprint: payload.refingerprint,
+    };
 
-            match video_hashes_result {
-                Ok(hashes) => {
-                    tracing::info!(
-                        "Generated {} video hashes, {} audio hashes",
-                        hashes.len(),
-                        audio_hashes.len()
-                    );
-
-                    // CALL WORKER API TO CHECK DUPLICATES & STORE
-                    let client = Client::new();
-                    let api_url = std::env::var("UPLOAD_API_URL")
-                        .unwrap_or_else(|_| "http://127.0.0.1:8787".to_string());
-                    let target_url = format!("{}/internal/complete", api_url);
+    if state.pool.try_submit(job).is_err() {
+        let message = "Processor is at capacity, retry later".to_string();
+        tracing::warn!("Rejecting video {}: queue full", payload.video_id);
+        state.jobs.remove(&id);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(
+                header::RETRY_AFTER,
+                state.pool.retry_after_secs().to_string(),
+            )],
+            Json(json!({ "error": message, "load": state.pool.load() })),
+        )
+            .into_response();
+    }
 
-                    let body = json!({
-                        "video_id": payload.video_id,
-                        "hashes": hashes,
-                        "audio_hashes": audio_hashes
-                    });
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "job_id": id,
+            "video_id": payload.video_id,
+            "status_url": format!("/jobs/{}", id)
+        })),
+    )
+        .into_response()
+}
 
-                    match client.post(&target_url).json(&body).send().await {
-                        Ok(res) => {
-                            if res.status() == StatusCode::OK {
-                                tracing::info!("Video indexed successfully via API");
-                                (
-                                    StatusCode::OK,
-                                    format!("Processed and indexed video {}", payload.video_id),
-                                )
-                            } else if res.status() == StatusCode::CONFLICT {
-                                tracing::warn!("Duplicate detected by API!");
-                                (
-                                    StatusCode::CONFLICT,
-                                    format!(
-                                        "Duplicate content detected for video {}",
-                                        payload.video_id
-                                    ),
-                                )
-                            } else {
-                                let err_text = res.text().await.unwrap_or_default();
-                                tracing::error!("API returned error: {}", err_text);
-                                (
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                    format!("Upload API Error: {}", err_text),
-                                )
-                            }
-                        }
-                        Err(e) => {
-                            tracing::error!("Failed to call Upload API: {}", e);
-                            (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                format!("Failed to contact Upload API: {}", e),
-                            )
-                        }
-                    }
-                }
-                Err(e) => {
-                    tracing::error!("Failed to generate fingerprints: {:?}", e);
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Failed to fingerprint video: {}", e),
-                    )
-                }
-            }
-        }
-        Err(e) => {
-            tracing::error!("Failed to download video: {:?}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to process video: {}", e),
-            )
-        }
+async fn job_status(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.jobs.get(&id) {
+        Some(job) => Json(job).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("Job {} not found", id)).into_response(),
     }
 }
+
+async fn load(State(state): State<AppState>) -> Json<pipeline::Load> {
+    Json(state.pool.load())
+}
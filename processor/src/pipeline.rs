@@ -0,0 +1,462 @@
+use crate::audio::shazam::{AudioConfig, AudioHash, AudioMode};
+use crate::audio::AudioFingerprintError;
+use crate::extract::{Input, SamplingMode, FRAMES_PER_SECOND, FRAME_HEIGHT, FRAME_WIDTH};
+use crate::fingerprint::hash::{HashAlgorithm, HashConfig};
+use crate::fingerprint::{FingerprintConfig, FrameHash};
+use crate::jobs::{JobStore, Stage, Warning};
+use crate::{audio, download, fingerprint};
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::primitives::ByteStream;
+use reqwest::{Client, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+
+/// Settings of the background pool, read from the environment at startup.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Jobs fingerprinted concurrently (`PROCESSOR_WORKERS`).
+    pub workers: usize,
+    /// Accepted jobs allowed to wait for a worker (`PROCESSOR_QUEUE_DEPTH`).
+    pub queue_depth: usize,
+    /// `Retry-After` sent when the queue is full (`PROCESSOR_RETRY_AFTER`).
+    pub retry_after_secs: u64,
+    /// Frames hashed per video (`FRAME_SAMPLING`: `fps`, `scene` or
+    /// `iframes`, tuned by `FRAME_SAMPLING_FPS` and `SCENE_THRESHOLD`) and
+    /// how (`HASH_ALGORITHM`, `HASH_SIZE`, `CROP_BORDERS`, `HASH_MIRRORED`,
+    /// `REGION_GRID`).
+    pub fingerprint: FingerprintConfig,
+    /// Audio hash kind (`AUDIO_FINGERPRINT_MODE`: `absolute` or `invariant`)
+    /// and spectral peak picking (`AUDIO_PEAK_TIME_RADIUS`,
+    /// `AUDIO_PEAK_FREQ_RADIUS`, `AUDIO_PEAK_THRESHOLD_DB`,
+    /// `AUDIO_PEAK_FLOOR_DB`).
+    pub audio: AudioConfig,
+}
+
+impl PoolConfig {
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+        let mut config = Self {
+            workers: var("PROCESSOR_WORKERS", 2).max(1),
+            queue_depth: var("PROCESSOR_QUEUE_DEPTH", 16).max(1),
+            retry_after_secs: var("PROCESSOR_RETRY_AFTER", 30),
+            fingerprint: FingerprintConfig {
+                sampling: match var("FRAME_SAMPLING", String::from("fps")).as_str() {
+                    "scene" => SamplingMode::SceneChange(var("SCENE_THRESHOLD", 0.3)),
+                    "iframes" => SamplingMode::IFrames,
+                    other => {
+                        if other != "fps" {
+                            tracing::warn!("Unknown FRAME_SAMPLING {:?}, using fps", other);
+                        }
+                        let default = FRAMES_PER_SECOND as f64;
+                        match var("FRAME_SAMPLING_FPS", default) {
+                            rate if rate > 0.0 && rate.is_finite() => SamplingMode::Fps(rate),
+                            rate => {
+                                tracing::warn!(
+                                    "FRAME_SAMPLING_FPS {} is not positive, using {}",
+                                    rate,
+                                    default
+                                );
+                                SamplingMode::Fps(default)
+                            }
+                        }
+                    }
+                },
+                crop_borders: var("CROP_BORDERS", false),
+                mirrored: var("HASH_MIRRORED", false),
+                region_grid: var("REGION_GRID", 0),
+                hash: HashConfig {
+                    algorithm: var("HASH_ALGORITHM", HashAlgorithm::DHash),
+                    size: match var("HASH_SIZE", 8) {
+                        size if size >= 4 && size % 4 == 0 => size,
+                        size => {
+                            tracing::warn!("HASH_SIZE {} is not a multiple of 4, using 8", size);
+                            8
+                        }
+                    },
+                },
+            },
+            audio: {
+                let default = AudioConfig::default();
+                AudioConfig {
+                    mode: var("AUDIO_FINGERPRINT_MODE", AudioMode::Absolute),
+                    time_radius: var("AUDIO_PEAK_TIME_RADIUS", default.time_radius),
+                    freq_radius: var("AUDIO_PEAK_FREQ_RADIUS", default.freq_radius),
+                    threshold_db: var("AUDIO_PEAK_THRESHOLD_DB", default.threshold_db),
+                    floor_db: var("AUDIO_PEAK_FLOOR_DB", default.floor_db),
+                }
+            },
+        };
+
+        // A region smaller than the hash grid would be hashed from upscaled
+        // pixels.
+        let fingerprint = &mut config.fingerprint;
+        let max_grid = FRAME_WIDTH.min(FRAME_HEIGHT) / fingerprint.hash.size;
+        if fingerprint.region_grid > max_grid {
+            tracing::warn!(
+                "REGION_GRID {} leaves cells smaller than the hash, using {}",
+                fingerprint.region_grid,
+                max_grid
+            );
+            fingerprint.region_grid = max_grid;
+        }
+        config
+    }
+}
+
+/// Snapshot of how busy the pool is, served by `GET /load`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Load {
+    pub workers: usize,
+    pub busy: usize,
+    pub queued: usize,
+    pub queue_depth: usize,
+    pub accepting: bool,
+}
+
+/// Handle used by the HTTP layer to submit jobs without blocking.
+#[derive(Clone)]
+pub struct Pool {
+    sender: mpsc::Sender<Job>,
+    busy: Arc<AtomicUsize>,
+    config: PoolConfig,
+}
+
+impl Pool {
+    /// Queues `job`, or hands it back when the queue is full.
+    pub fn try_submit(&self, job: Job) -> std::result::Result<(), Job> {
+        self.sender.try_send(job).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(job) | mpsc::error::TrySendError::Closed(job) => job,
+        })
+    }
+
+    pub fn load(&self) -> Load {
+        let queued = self.config.queue_depth - self.sender.capacity();
+        Load {
+            workers: self.config.workers,
+            busy: self.busy.load(Ordering::Relaxed),
+            queued,
+            queue_depth: self.config.queue_depth,
+            accepting: queued < self.config.queue_depth,
+        }
+    }
+
+    pub fn retry_after_secs(&self) -> u64 {
+        self.config.retry_after_secs
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub video_id: String,
+    pub r2_key: String,
+    /// Re-fingerprinting a video whose stored hashes are from an older
+    /// version. The Upload API swaps the hashes without matching again, and a
+    /// failure leaves the video as it was.
+    pub refingerprint: bool,
+}
+
+/// A failure that may not happen again, such as R2 or the Upload API being
+/// unreachable. The Upload API sends such jobs again after a delay instead of
+/// failing the video.
+#[derive(Debug)]
+struct Transient(anyhow::Error);
+
+impl fmt::Display for Transient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for Transient {}
+
+fn transient(e: impl Into<anyhow::Error>) -> anyhow::Error {
+    anyhow::Error::new(Transient(e.into()))
+}
+
+fn upload_api_url() -> String {
+    std::env::var("UPLOAD_API_URL").unwrap_or_else(|_| "http://127.0.0.1:8787".to_string())
+}
+
+/// Starts the background pool. Each worker pulls the next job off the queue
+/// and records its progress in `jobs`; a job that errors or panics is marked
+/// failed and reported to the Upload API.
+pub fn start(config: PoolConfig, jobs: JobStore) -> Pool {
+    let (sender, receiver) = mpsc::channel::<Job>(config.queue_depth);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let busy = Arc::new(AtomicUsize::new(0));
+
+    for worker in 0..config.workers {
+        let receiver = receiver.clone();
+        let jobs = jobs.clone();
+        let busy = busy.clone();
+        let fingerprint = config.fingerprint.clone();
+        let audio_config = config.audio.clone();
+        tokio::spawn(async move {
+            loop {
+                let Some(job) = receiver.lock().await.recv().await else {
+                    break;
+                };
+                tracing::info!(
+                    "Worker {} picked up job {} ({})",
+                    worker,
+                    job.id,
+                    job.video_id
+                );
+
+                busy.fetch_add(1, Ordering::Relaxed);
+                // Run in a task of its own so a panic while fingerprinting
+                // fails this job instead of taking the worker down with it.
+                let task = {
+                    let job = job.clone();
+                    let jobs = jobs.clone();
+                    let fingerprint = fingerprint.clone();
+                    let audio_config = audio_config.clone();
+                    tokio::spawn(async move { run(&job, &jobs, &fingerprint, &audio_config).await })
+                };
+                let result = task
+                    .await
+                    .unwrap_or_else(|e| Err(anyhow!("Job panicked: {}", e)));
+                match result {
+                    Ok(result) => jobs.complete(&job.id, result),
+                    Err(e) => {
+                        tracing::error!("Job {} failed: {:?}", job.id, e);
+                        jobs.fail(&job.id, e.to_string());
+                        if !job.refingerprint {
+                            report_failure(&job, &e.to_string(), e.is::<Transient>()).await;
+                        }
+                    }
+                }
+                busy.fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+    }
+
+    Pool {
+        sender,
+        busy,
+        config,
+    }
+}
+
+/// Frames are hashed while ffmpeg is still decoding, before their number is
+/// known, so the first one only moves the job to the hashing stage.
+fn hashing_progress(job: &Job, jobs: &JobStore) -> impl Fn(usize) + Send + 'static {
+    let (id, jobs) = (job.id.clone(), jobs.clone());
+    move |done| {
+        if done == 1 {
+            jobs.update(&id, Stage::Hashing, 0.3);
+        }
+    }
+}
+
+/// Streams the video from R2 straight into ffmpeg; nothing touches the disk.
+async fn fingerprint_stream(
+    stream: ByteStream,
+    config: &FingerprintConfig,
+    audio_config: &AudioConfig,
+    job: &Job,
+    jobs: &JobStore,
+) -> Result<(Vec<FrameHash>, Vec<AudioHash>)> {
+    jobs.update(&job.id, Stage::ExtractingFrames, 0.1);
+    let extracted = fingerprint::extract_and_hash(
+        Input::Stream(stream),
+        config,
+        true,
+        hashing_progress(job, jobs),
+    )
+    .await?;
+    fingerprint_extracted(extracted, audio_config, job, jobs)
+}
+
+fn fingerprint_extracted(
+    (hashes, samples): (Vec<FrameHash>, Vec<f32>),
+    audio_config: &AudioConfig,
+    job: &Job,
+    jobs: &JobStore,
+) -> Result<(Vec<FrameHash>, Vec<AudioHash>)> {
+    tracing::info!(
+        "Extracted {} frames, {} audio samples",
+        hashes.len(),
+        samples.len()
+    );
+
+    jobs.update(&job.id, Stage::Audio, 0.7);
+    let audio_hashes = audio_or_warn(
+        audio::shazam::fingerprint_samples(&samples, audio_config),
+        job,
+        jobs,
+    );
+
+    Ok((hashes, audio_hashes))
+}
+
+/// A video without usable audio is still matched on its frames; the reason
+/// is recorded on the job and sent along with the callback.
+fn audio_or_warn(
+    result: std::result::Result<Vec<AudioHash>, AudioFingerprintError>,
+    job: &Job,
+    jobs: &JobStore,
+) -> Vec<AudioHash> {
+    result.unwrap_or_else(|e| {
+        tracing::warn!("No audio fingerprint for job {}: {}", job.id, e);
+        jobs.warn(
+            &job.id,
+            Warning {
+                stage: Stage::Audio,
+                kind: e.kind(),
+                message: e.to_string(),
+            },
+        );
+        Vec::new()
+    })
+}
+
+/// Fallback for inputs the single streamed pass cannot handle: an MP4 whose
+/// `moov` atom sits at the end, or a video without an audio track. The
+/// temporary copy is deleted on return.
+async fn fingerprint_file(
+    config: &FingerprintConfig,
+    audio_config: &AudioConfig,
+    job: &Job,
+    jobs: &JobStore,
+) -> Result<(Vec<FrameHash>, Vec<AudioHash>)> {
+    jobs.update(&job.id, Stage::Downloading, 0.0);
+    let video = download::download_video(&job.video_id, &job.r2_key)
+        .await
+        .map_err(transient)?;
+
+    jobs.update(&job.id, Stage::ExtractingFrames, 0.1);
+    match fingerprint::extract_and_hash(
+        Input::File(video.path()),
+        config,
+        true,
+        hashing_progress(job, jobs),
+    )
+    .await
+    {
+        Ok(extracted) => return fingerprint_extracted(extracted, audio_config, job, jobs),
+        Err(e) => tracing::warn!("Combined extraction failed ({}), extracting separately", e),
+    }
+
+    let hashes =
+        fingerprint::process_video(video.path(), config, hashing_progress(job, jobs)).await?;
+
+    jobs.update(&job.id, Stage::Audio, 0.7);
+    let audio_hashes = audio_or_warn(
+        audio::shazam::compute_audio_fingerprints(video.path(), audio_config),
+        job,
+        jobs,
+    );
+
+    Ok((hashes, audio_hashes))
+}
+
+/// Fingerprints the video and hands the results to the Upload API.
+async fn run(
+    job: &Job,
+    jobs: &JobStore,
+    config: &FingerprintConfig,
+    audio_config: &AudioConfig,
+) -> Result<serde_json::Value> {
+    jobs.update(&job.id, Stage::Downloading, 0.0);
+    let stream = download::open_video(&job.r2_key).await.map_err(transient)?;
+    let (hashes, audio_hashes) =
+        match fingerprint_stream(stream, config, audio_config, job, jobs).await {
+            Ok(fingerprints) => fingerprints,
+            Err(e) => {
+                tracing::warn!(
+                    "Streaming failed for job {} ({}), retrying from a temporary file",
+                    job.id,
+                    e
+                );
+                fingerprint_file(config, audio_config, job, jobs).await?
+            }
+        };
+    tracing::info!(
+        "Generated {} video hashes, {} audio hashes",
+        hashes.len(),
+        audio_hashes.len()
+    );
+
+    jobs.update(&job.id, Stage::Callback, 0.9);
+    let warnings = jobs.get(&job.id).map(|j| j.warnings).unwrap_or_default();
+    let body = json!({
+        "video_id": job.video_id,
+        "hashes": hashes,
+        "audio_hashes": audio_hashes,
+        "hash_algorithm": config.tag(),
+        "visual_fingerprint_version": fingerprint::FINGERPRINT_VERSION,
+        "audio_fingerprint_version": audio::shazam::FINGERPRINT_VERSION,
+        "refingerprint": job.refingerprint,
+        "frame_interval": frame_interval(&config.sampling, &hashes),
+        "audio_offset_seconds": audio::shazam::SECONDS_PER_OFFSET,
+        "warnings": warnings
+    });
+    let res = Client::new()
+        .post(format!("{}/internal/complete", upload_api_url()))
+        .json(&body)
+        .send()
+        .await
+        .map_err(|e| transient(anyhow!("Failed to contact Upload API: {}", e)))?;
+
+    let status = res.status();
+    match status {
+        // The body is the worker's verdict and, for flagged videos, its match report.
+        StatusCode::OK | StatusCode::ACCEPTED | StatusCode::CONFLICT => {
+            let body = res.json::<serde_json::Value>().await.unwrap_or_default();
+            match status {
+                StatusCode::OK => tracing::info!("Video indexed successfully via API"),
+                StatusCode::ACCEPTED => tracing::warn!("Video held for human review by API"),
+                _ => tracing::warn!("Duplicate detected by API: {}", body),
+            }
+            Ok(json!({ "status_code": status.as_u16(), "body": body }))
+        }
+        _ => {
+            let err_text = res.text().await.unwrap_or_default();
+            let e = anyhow!("Upload API Error ({}): {}", status, err_text);
+            match status {
+                StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => Err(transient(e)),
+                _ if status.is_server_error() => Err(transient(e)),
+                _ => Err(e),
+            }
+        }
+    }
+}
+
+/// Typical seconds between consecutive hashes, which the worker uses as its
+/// alignment tolerance: the sampling period for fixed-rate sampling,
+/// otherwise the average spacing. Always positive, even when every frame
+/// carries the same timestamp.
+fn frame_interval(sampling: &SamplingMode, hashes: &[FrameHash]) -> f64 {
+    match (sampling, hashes.first(), hashes.last()) {
+        (SamplingMode::Fps(rate), _, _) => 1.0 / rate,
+        (_, Some(first), Some(last)) if last.timestamp_ms > first.timestamp_ms => {
+            (last.timestamp_ms - first.timestamp_ms) as f64 / 1000.0 / (hashes.len() - 1) as f64
+        }
+        _ => 1.0 / FRAMES_PER_SECOND as f64,
+    }
+}
+
+/// Tells the Upload API that a job it dispatched did not complete, so the
+/// video does not stay `processing` forever. A `retryable` failure is sent
+/// back to the processor later.
+async fn report_failure(job: &Job, error: &str, retryable: bool) {
+    let result = Client::new()
+        .post(format!("{}/internal/failed", upload_api_url()))
+        .json(&json!({ "video_id": job.video_id, "error": error, "retryable": retryable }))
+        .send()
+        .await;
+    if let Err(e) = result {
+        tracing::error!("Failed to report failure of job {}: {}", job.id, e);
+    }
+}
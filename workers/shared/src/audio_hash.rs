@@ -0,0 +1,293 @@
+//! Bit layout of audio fingerprint hashes.
+//!
+//! A hash packs one pair of spectral peaks into the low 52 bits of a `u64`,
+//! so it survives the round trip through JSON and D1, which hand numbers to
+//! JavaScript as doubles:
+//!
+//! ```text
+//!  51    48 47          32 31          16 15           0
+//! +--------+--------------+--------------+--------------+
+//! | layout |   field a    |   field b    |   field c    |
+//! +--------+--------------+--------------+--------------+
+//! ```
+//!
+//! The 4-bit `layout` says how the three 16-bit fields are read, so new kinds
+//! of hashes never collide with stored ones. Layout `0` is the unversioned
+//! packing that came before, `(anchor << 23) | (target << 9) | time_delta`,
+//! kept so hashes already in the index still decode.
+
+use std::fmt;
+
+/// Number of bits a hash may use.
+pub const HASH_BITS: u32 = 52;
+
+/// Largest valid hash. Every hash is an integer a double holds exactly.
+pub const MAX_HASH: u64 = (1 << HASH_BITS) - 1;
+
+const FIELD_BITS: u32 = 16;
+const FIELD_MASK: u64 = (1 << FIELD_BITS) - 1;
+const LAYOUT_SHIFT: u32 = 3 * FIELD_BITS;
+
+/// One anchor/target peak pair, in the layouts the fingerprinter can emit.
+/// Bins are FFT bin indexes and time deltas count analysis hops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AudioHashKey {
+    /// Layout 0: the original packing. Only `anchor_bin < 2^25`,
+    /// `target_bin < 2^14` and `time_delta < 2^9` survive encoding.
+    Legacy {
+        anchor_bin: u32,
+        target_bin: u16,
+        time_delta: u16,
+    },
+    /// Layout 1: both peaks at their absolute frequency.
+    Absolute {
+        anchor_bin: u16,
+        target_bin: u16,
+        time_delta: u16,
+    },
+    /// Layout 2: the target relative to the anchor, in bins.
+    FrequencyDelta {
+        anchor_bin: u16,
+        bin_delta: i16,
+        time_delta: u16,
+    },
+    /// Layout 3: an anchor and two targets described only by ratios, so the
+    /// hash survives pitch shifts and tempo changes. `near_interval` and
+    /// `far_interval` are the pitch intervals from the anchor to each target
+    /// and `time_ratio` is the near target's time delta as a share of the far
+    /// one's, all quantized by the fingerprinter.
+    Ratio {
+        near_interval: i16,
+        far_interval: i16,
+        time_ratio: u16,
+    },
+}
+
+/// Why a number is not a valid hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Uses bits above `HASH_BITS`.
+    OutOfRange(u64),
+    /// The layout nibble names no known layout.
+    UnknownLayout(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::OutOfRange(hash) => {
+                write!(f, "audio hash {} is wider than {} bits", hash, HASH_BITS)
+            }
+            DecodeError::UnknownLayout(layout) => write!(f, "unknown audio hash layout {}", layout),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl AudioHashKey {
+    /// The layout nibble this key is encoded with.
+    pub fn layout(&self) -> u8 {
+        match self {
+            AudioHashKey::Legacy { .. } => 0,
+            AudioHashKey::Absolute { .. } => 1,
+            AudioHashKey::FrequencyDelta { .. } => 2,
+            AudioHashKey::Ratio { .. } => 3,
+        }
+    }
+
+    pub fn encode(self) -> u64 {
+        let fields = |a: u16, b: u16, c: u16| {
+            (self.layout() as u64) << LAYOUT_SHIFT
+                | (a as u64) << (2 * FIELD_BITS)
+                | (b as u64) << FIELD_BITS
+                | c as u64
+        };
+        match self {
+            AudioHashKey::Legacy {
+                anchor_bin,
+                target_bin,
+                time_delta,
+            } => {
+                ((anchor_bin as u64 & 0x1ff_ffff) << 23)
+                    | ((target_bin as u64 & 0x3fff) << 9)
+                    | (time_delta as u64 & 0x1ff)
+            }
+            AudioHashKey::Absolute {
+                anchor_bin,
+                target_bin,
+                time_delta,
+            } => fields(anchor_bin, target_bin, time_delta),
+            AudioHashKey::FrequencyDelta {
+                anchor_bin,
+                bin_delta,
+                time_delta,
+            } => fields(anchor_bin, bin_delta as u16, time_delta),
+            AudioHashKey::Ratio {
+                near_interval,
+                far_interval,
+                time_ratio,
+            } => fields(near_interval as u16, far_interval as u16, time_ratio),
+        }
+    }
+
+    pub fn decode(hash: u64) -> Result<Self, DecodeError> {
+        if hash > MAX_HASH {
+            return Err(DecodeError::OutOfRange(hash));
+        }
+        let field = |i: u32| ((hash >> (i * FIELD_BITS)) & FIELD_MASK) as u16;
+        match (hash >> LAYOUT_SHIFT) as u8 {
+            0 => Ok(AudioHashKey::Legacy {
+                anchor_bin: (hash >> 23) as u32,
+                target_bin: ((hash >> 9) & 0x3fff) as u16,
+                time_delta: (hash & 0x1ff) as u16,
+            }),
+            1 => Ok(AudioHashKey::Absolute {
+                anchor_bin: field(2),
+                target_bin: field(1),
+                time_delta: field(0),
+            }),
+            2 => Ok(AudioHashKey::FrequencyDelta {
+                anchor_bin: field(2),
+                bin_delta: field(1) as i16,
+                time_delta: field(0),
+            }),
+            3 => Ok(AudioHashKey::Ratio {
+                near_interval: field(2) as i16,
+                far_interval: field(1) as i16,
+                time_ratio: field(0),
+            }),
+            layout => Err(DecodeError::UnknownLayout(layout)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic xorshift so the property tests need no dependencies.
+    fn values(seed: u64, count: usize) -> impl Iterator<Item = u64> {
+        let mut state = seed;
+        (0..count).map(move |_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        })
+    }
+
+    fn edges() -> [u16; 5] {
+        [0, 1, 0x7fff, 0x8000, u16::MAX]
+    }
+
+    fn round_trips(key: AudioHashKey) {
+        let hash = key.encode();
+        assert!(hash <= MAX_HASH, "{:?} encodes to {:#x}", key, hash);
+        assert_eq!(AudioHashKey::decode(hash), Ok(key), "hash {:#x}", hash);
+        assert_eq!(hash as f64 as u64, hash, "{:#x} is not exact as f64", hash);
+    }
+
+    #[test]
+    fn absolute_round_trips() {
+        for v in values(0x9e37_79b9_7f4a_7c15, 10_000) {
+            round_trips(AudioHashKey::Absolute {
+                anchor_bin: v as u16,
+                target_bin: (v >> 16) as u16,
+                time_delta: (v >> 32) as u16,
+            });
+        }
+        for a in edges() {
+            for b in edges() {
+                for c in edges() {
+                    round_trips(AudioHashKey::Absolute {
+                        anchor_bin: a,
+                        target_bin: b,
+                        time_delta: c,
+                    });
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn frequency_delta_round_trips() {
+        for v in values(0x2545_f491_4f6c_dd1d, 10_000) {
+            round_trips(AudioHashKey::FrequencyDelta {
+                anchor_bin: v as u16,
+                bin_delta: (v >> 16) as i16,
+                time_delta: (v >> 32) as u16,
+            });
+        }
+        for delta in [i16::MIN, -1, 0, 1, i16::MAX] {
+            round_trips(AudioHashKey::FrequencyDelta {
+                anchor_bin: 40,
+                bin_delta: delta,
+                time_delta: 3,
+            });
+        }
+    }
+
+    #[test]
+    fn ratio_round_trips() {
+        for v in values(0x5851_f42d_4c95_7f2d, 10_000) {
+            round_trips(AudioHashKey::Ratio {
+                near_interval: v as i16,
+                far_interval: (v >> 16) as i16,
+                time_ratio: (v >> 32) as u16,
+            });
+        }
+    }
+
+    #[test]
+    fn legacy_round_trips() {
+        for v in values(0xdead_beef_cafe_f00d, 10_000) {
+            round_trips(AudioHashKey::Legacy {
+                anchor_bin: (v & 0x1ff_ffff) as u32,
+                target_bin: ((v >> 25) & 0x3fff) as u16,
+                time_delta: ((v >> 39) & 0x1ff) as u16,
+            });
+        }
+    }
+
+    #[test]
+    fn legacy_matches_original_packing() {
+        let (f1, f2, dt) = (160u64, 511u64, 6u64);
+        let original = (f1 << 23) | (f2 << 9) | dt;
+        assert_eq!(
+            AudioHashKey::decode(original),
+            Ok(AudioHashKey::Legacy {
+                anchor_bin: 160,
+                target_bin: 511,
+                time_delta: 6,
+            })
+        );
+    }
+
+    #[test]
+    fn layouts_never_collide() {
+        let absolute = AudioHashKey::Absolute {
+            anchor_bin: 40,
+            target_bin: 80,
+            time_delta: 2,
+        };
+        let delta = AudioHashKey::FrequencyDelta {
+            anchor_bin: 40,
+            bin_delta: 80,
+            time_delta: 2,
+        };
+        assert_ne!(absolute.encode(), delta.encode());
+    }
+
+    #[test]
+    fn rejects_invalid_hashes() {
+        assert_eq!(
+            AudioHashKey::decode(1 << HASH_BITS),
+            Err(DecodeError::OutOfRange(1 << HASH_BITS))
+        );
+        assert_eq!(
+            AudioHashKey::decode(0xf << LAYOUT_SHIFT),
+            Err(DecodeError::UnknownLayout(0xf))
+        );
+    }
+}
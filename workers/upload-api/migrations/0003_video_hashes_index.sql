@@ -0,0 +1,3 @@
+-- Migration number: 0003 	 2024-02-05T00:00:00Z
+
+CREATE INDEX idx_video_hashes_video ON video_hashes(video_id, frame_index);
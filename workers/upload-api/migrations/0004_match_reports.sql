@@ -0,0 +1,20 @@
+-- Migration number: 0004 	 2024-02-12T00:00:00Z
+
+CREATE TABLE match_reports (
+    id TEXT PRIMARY KEY,
+    video_id TEXT NOT NULL,
+    candidate_video_id TEXT NOT NULL,
+    visual_similarity REAL NOT NULL,
+    audio_similarity REAL NOT NULL,
+    confidence REAL NOT NULL,
+    matched_frames INTEGER NOT NULL,
+    threshold REAL NOT NULL,
+    query_start REAL,
+    query_end REAL,
+    reference_start REAL,
+    reference_end REAL,
+    created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    FOREIGN KEY(video_id) REFERENCES videos(id)
+);
+
+CREATE INDEX idx_match_reports_video ON match_reports(video_id);
@@ -0,0 +1,9 @@
+-- Migration number: 0005 	 2024-02-19T00:00:00Z
+
+-- Uploads are now blocked, held for review, or activated by the tiered policy.
+UPDATE videos SET status = 'blocked' WHERE status = 'duplicate';
+
+ALTER TABLE match_reports ADD COLUMN decision TEXT;
+
+UPDATE match_reports
+SET decision = CASE WHEN confidence >= threshold THEN 'blocked' ELSE 'active' END;
@@ -0,0 +1,3 @@
+-- Migration number: 0006 	 2024-02-26T00:00:00Z
+
+ALTER TABLE videos ADD COLUMN processed_at TEXT;
@@ -0,0 +1,5 @@
+-- Migration number: 0007 	 2024-03-04T00:00:00Z
+
+CREATE INDEX idx_videos_created ON videos(created_at, id);
+CREATE INDEX idx_videos_user_created ON videos(user_id, created_at, id);
+CREATE INDEX idx_videos_status_created ON videos(status, created_at, id);
@@ -0,0 +1,6 @@
+-- Migration number: 0008 	 2024-03-11T00:00:00Z
+
+-- Presentation time of each sampled frame. Everything indexed so far was
+-- sampled at one frame per second.
+ALTER TABLE video_hashes ADD COLUMN timestamp_ms INTEGER;
+UPDATE video_hashes SET timestamp_ms = frame_index * 1000 WHERE timestamp_ms IS NULL;
@@ -0,0 +1,9 @@
+-- Migration number: 0009 	 2024-03-18T00:00:00Z
+
+-- Algorithm and size of each frame hash. Everything indexed so far used
+-- img_hash's default 8x8 gradient hash.
+ALTER TABLE video_hashes ADD COLUMN hash_algorithm TEXT NOT NULL DEFAULT 'dhash-8';
+ALTER TABLE video_lsh_bands ADD COLUMN hash_algorithm TEXT NOT NULL DEFAULT 'dhash-8';
+
+DROP INDEX IF EXISTS idx_lsh_bands;
+CREATE INDEX idx_lsh_bands ON video_lsh_bands(hash_algorithm, band_index, band_value);
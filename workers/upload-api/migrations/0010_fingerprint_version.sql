@@ -0,0 +1,13 @@
+-- Migration number: 0010 	 2024-03-25T00:00:00Z
+
+-- Version of the processor code that produced each hash. Hashes of different
+-- versions are never compared; older videos are re-fingerprinted through
+-- POST /internal/backfill.
+ALTER TABLE video_hashes ADD COLUMN fingerprint_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE video_lsh_bands ADD COLUMN fingerprint_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE audio_hashes ADD COLUMN fingerprint_version INTEGER NOT NULL DEFAULT 1;
+
+DROP INDEX IF EXISTS idx_lsh_bands;
+CREATE INDEX idx_lsh_bands ON video_lsh_bands(hash_algorithm, fingerprint_version, band_index, band_value);
+CREATE INDEX idx_video_hashes_version ON video_hashes(fingerprint_version, video_id);
+CREATE INDEX idx_audio_hashes_version ON audio_hashes(fingerprint_version, video_id);
@@ -0,0 +1,4 @@
+-- Migration number: 0011 	 2024-04-01T00:00:00Z
+
+-- Deleting a video also removes the reports that name it as the candidate.
+CREATE INDEX idx_match_reports_candidate ON match_reports(candidate_video_id);
@@ -0,0 +1,5 @@
+-- Migration number: 0012 	 2024-04-08T00:00:00Z
+
+-- When a moderator let a blocked video through. Processing then indexes it
+-- and makes it active without matching it again.
+ALTER TABLE videos ADD COLUMN approved_at TEXT;
@@ -0,0 +1,10 @@
+-- Migration number: 0013 	 2024-04-15T00:00:00Z
+
+-- When each video last entered `processing`, and how often its job has been
+-- sent again since. Videos still processing after PROCESSING_TIMEOUT are
+-- re-queued by the scheduled sweep.
+ALTER TABLE videos ADD COLUMN processing_started_at INTEGER;
+ALTER TABLE videos ADD COLUMN processing_attempts INTEGER NOT NULL DEFAULT 0;
+UPDATE videos SET processing_started_at = created_at WHERE status = 'processing';
+
+CREATE INDEX idx_videos_processing ON videos(status, processing_started_at);
@@ -0,0 +1,83 @@
+use std::str::FromStr;
+use worker::Env;
+
+/// Reads a worker var and parses it, falling back to `default` when the var is
+/// missing or malformed.
+pub fn env_or<T: FromStr>(env: &Env, name: &str, default: T) -> T {
+    env.var(name)
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .unwrap_or(default)
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchConfig {
+    /// Largest share of a frame hash's bits that may differ for two frames to
+    /// count as the same. Scaled to the hash length, so a `HASH_SIZE` of 4 and
+    /// of 16 are equally strict.
+    pub hamming_ratio: f64,
+    /// Fraction of query frames that must match a candidate to flag a duplicate.
+    pub visual_match_ratio: f64,
+    /// How many LSH candidates are scored in full.
+    pub max_candidates: usize,
+    /// An aligned run at least this long is flagged as an excerpt even when it
+    /// covers only a small part of the upload. Audio matches shorter than
+    /// this are dropped.
+    pub min_segment_seconds: f64,
+    /// Largest gap, in seconds, between aligned frames of the same run. Sparse
+    /// sampling widens it to a few of the upload's frame intervals.
+    pub segment_max_gap: f64,
+    /// Upper bound on distinct audio hashes looked up per upload.
+    pub audio_query_hashes: usize,
+    /// Minimum aligned hash hits for an audio match.
+    pub audio_min_votes: usize,
+    /// How many times larger the winning delta bin must be than the runner-up.
+    pub audio_peak_ratio: f64,
+    /// Width, in `time_offset` steps, of the delta bins pitch/tempo-invariant
+    /// hashes vote in. A sped-up copy drifts against the original as it plays,
+    /// so its hits spread over neighbouring deltas.
+    pub audio_invariant_bin: i64,
+}
+
+impl MatchConfig {
+    pub fn from_env(env: &Env) -> Self {
+        Self {
+            hamming_ratio: env_or(env, "HAMMING_RATIO", 0.16),
+            visual_match_ratio: env_or(env, "VISUAL_MATCH_RATIO", 0.5),
+            max_candidates: env_or(env, "MAX_MATCH_CANDIDATES", 10),
+            min_segment_seconds: env_or(env, "MIN_SEGMENT_SECONDS", 10.0),
+            segment_max_gap: env_or(env, "SEGMENT_MAX_GAP", 3.0),
+            audio_query_hashes: env_or(env, "AUDIO_QUERY_HASHES", 2000),
+            audio_min_votes: env_or(env, "AUDIO_MIN_VOTES", 10),
+            audio_peak_ratio: env_or(env, "AUDIO_PEAK_RATIO", 3.0),
+            audio_invariant_bin: env_or(env, "AUDIO_INVARIANT_BIN", 8),
+        }
+    }
+
+    /// Maximum Hamming distance between two `bits`-bit frame hashes that still
+    /// counts as the same frame: 10 bits of a 64-bit hash by default.
+    pub fn hamming_threshold(&self, bits: usize) -> u32 {
+        (bits as f64 * self.hamming_ratio).round() as u32
+    }
+}
+
+/// Fingerprint versions and frame hash algorithm the processor currently
+/// produces. Indexed videos with older or differently tagged hashes are picked
+/// up by the backfill.
+#[derive(Debug, Clone)]
+pub struct FingerprintVersions {
+    pub visual: u32,
+    pub audio: u32,
+    /// Tag of the processor's frame hashes, e.g. `dhash-8` or `phash-16-crop`.
+    pub hash_algorithm: String,
+}
+
+impl FingerprintVersions {
+    pub fn from_env(env: &Env) -> Self {
+        Self {
+            visual: env_or(env, "VISUAL_FINGERPRINT_VERSION", 2),
+            audio: env_or(env, "AUDIO_FINGERPRINT_VERSION", 4),
+            hash_algorithm: env_or(env, "HASH_ALGORITHM_TAG", "dhash-8".to_string()),
+        }
+    }
+}
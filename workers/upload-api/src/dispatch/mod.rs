@@ -0,0 +1,183 @@
+use crate::config::env_or;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+/// Message placed on `VIDEO_QUEUE` for every video that needs fingerprinting.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProcessingJob {
+    pub video_id: String,
+    pub r2_key: String,
+    /// Replace the fingerprints of an indexed video without matching it again
+    /// or touching its status.
+    #[serde(default)]
+    pub refingerprint: bool,
+    /// Deliveries that already failed. Retries are re-sent with this bumped,
+    /// since the queue does not expose its own delivery count.
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+/// How the processor answered a job.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Done,
+    /// Transient failure; `retry_after` is the processor's hint in seconds.
+    Retry {
+        retry_after: Option<u32>,
+    },
+    /// The job can never succeed, e.g. the request was rejected as malformed.
+    Permanent(String),
+}
+
+impl Outcome {
+    pub fn from_status(status: u16, retry_after: Option<u32>, body: String) -> Self {
+        match status {
+            // 409 and 202 are decisions about the video, not failures.
+            200..=299 | 409 => Outcome::Done,
+            408 | 429 | 500..=599 => Outcome::Retry { retry_after },
+            _ => Outcome::Permanent(format!("processor returned {}: {}", status, body)),
+        }
+    }
+}
+
+/// What the consumer should do with a message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Ack,
+    Retry { delay_seconds: u32 },
+    DeadLetter { reason: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay_seconds: u32,
+    pub max_delay_seconds: u32,
+    /// How long a video may stay `processing` after its job was accepted
+    /// before the job is taken as lost and sent again.
+    pub processing_timeout_seconds: u32,
+}
+
+impl RetryPolicy {
+    pub fn from_env(env: &Env) -> Self {
+        Self {
+            max_attempts: env_or(env, "DISPATCH_MAX_ATTEMPTS", 5),
+            base_delay_seconds: env_or(env, "DISPATCH_BASE_DELAY", 10),
+            max_delay_seconds: env_or(env, "DISPATCH_MAX_DELAY", 600),
+            processing_timeout_seconds: env_or(env, "PROCESSING_TIMEOUT", 3600),
+        }
+    }
+
+    /// Exponential backoff for the given 1-based delivery attempt.
+    pub fn backoff(&self, attempt: u32) -> u32 {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.base_delay_seconds
+            .saturating_mul(factor)
+            .min(self.max_delay_seconds)
+    }
+
+    pub fn decide(&self, outcome: Outcome, attempt: u32) -> Action {
+        match outcome {
+            Outcome::Done => Action::Ack,
+            Outcome::Permanent(reason) => Action::DeadLetter { reason },
+            Outcome::Retry { .. } if attempt >= self.max_attempts => Action::DeadLetter {
+                reason: format!("gave up after {} attempts", attempt),
+            },
+            Outcome::Retry { retry_after } => Action::Retry {
+                delay_seconds: retry_after
+                    .unwrap_or_else(|| self.backoff(attempt))
+                    .min(self.max_delay_seconds),
+            },
+        }
+    }
+}
+
+/// The fingerprinting service. Implemented over HTTP in production and by an
+/// in-memory stand-in in tests.
+#[allow(async_fn_in_trait)]
+pub trait Processor {
+    async fn process(&self, job: &ProcessingJob) -> Outcome;
+}
+
+/// Somewhere jobs can be sent: `VIDEO_QUEUE`, the dead-letter queue, or a
+/// local stand-in.
+#[allow(async_fn_in_trait)]
+pub trait JobQueue {
+    async fn send(&self, job: &ProcessingJob) -> Result<()>;
+}
+
+impl JobQueue for Queue {
+    async fn send(&self, job: &ProcessingJob) -> Result<()> {
+        Queue::send(self, job).await
+    }
+}
+
+/// Calls the processor's `/process` endpoint.
+pub struct HttpProcessor {
+    pub url: String,
+}
+
+impl HttpProcessor {
+    pub fn from_env(env: &Env) -> Self {
+        let base: String = env_or(env, "PROCESSOR_URL", "http://127.0.0.1:8080".to_string());
+        Self {
+            url: format!("{}/process", base.trim_end_matches('/')),
+        }
+    }
+}
+
+impl Processor for HttpProcessor {
+    async fn process(&self, job: &ProcessingJob) -> Outcome {
+        let response: Result<Response> = async {
+            let headers = Headers::new();
+            headers.set("Content-Type", "application/json")?;
+            let mut init = RequestInit::new();
+            init.with_method(Method::Post)
+                .with_headers(headers)
+                .with_body(Some(serde_json::to_string(job)?.into()));
+            Fetch::Request(Request::new_with_init(&self.url, &init)?)
+                .send()
+                .await
+        }
+        .await;
+
+        match response {
+            Ok(mut res) => {
+                let retry_after = res
+                    .headers()
+                    .get("Retry-After")
+                    .ok()
+                    .flatten()
+                    .and_then(|v| v.trim().parse().ok());
+                let body = res.text().await.unwrap_or_default();
+                Outcome::from_status(res.status_code(), retry_after, body)
+            }
+            // Network errors are worth retrying: the processor may be restarting.
+            Err(e) => {
+                console_log!("Failed to reach processor for {}: {}", job.video_id, e);
+                Outcome::Retry { retry_after: None }
+            }
+        }
+    }
+}
+
+/// Runs one delivery of `job` and decides whether to ack, retry or give up.
+/// Jobs that are given up on are forwarded to `dead_letters`.
+pub async fn handle(
+    processor: &impl Processor,
+    dead_letters: &impl JobQueue,
+    job: &ProcessingJob,
+    attempt: u32,
+    policy: &RetryPolicy,
+) -> Result<Action> {
+    let action = policy.decide(processor.process(job).await, attempt);
+    if let Action::DeadLetter { .. } = &action {
+        dead_letters.send(job).await?;
+    }
+    Ok(action)
+}
+
+#[cfg(test)]
+mod tests;
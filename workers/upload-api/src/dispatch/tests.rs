@@ -0,0 +1,166 @@
+use crate::dispatch::{handle, Action, JobQueue, Outcome, ProcessingJob, Processor, RetryPolicy};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+/// The stand-ins below never suspend, so a single poll drives them to
+/// completion.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut cx = Context::from_waker(Waker::noop());
+    match pin!(future).poll(&mut cx) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("stand-in future suspended"),
+    }
+}
+
+/// Replays scripted outcomes, then succeeds.
+struct ScriptedProcessor {
+    outcomes: RefCell<VecDeque<Outcome>>,
+    calls: RefCell<usize>,
+}
+
+impl ScriptedProcessor {
+    fn new(outcomes: Vec<Outcome>) -> Self {
+        Self {
+            outcomes: RefCell::new(outcomes.into()),
+            calls: RefCell::new(0),
+        }
+    }
+}
+
+impl Processor for ScriptedProcessor {
+    async fn process(&self, _job: &ProcessingJob) -> Outcome {
+        *self.calls.borrow_mut() += 1;
+        self.outcomes
+            .borrow_mut()
+            .pop_front()
+            .unwrap_or(Outcome::Done)
+    }
+}
+
+#[derive(Default)]
+struct LocalQueue {
+    sent: RefCell<Vec<ProcessingJob>>,
+}
+
+impl JobQueue for LocalQueue {
+    async fn send(&self, job: &ProcessingJob) -> worker::Result<()> {
+        self.sent.borrow_mut().push(job.clone());
+        Ok(())
+    }
+}
+
+fn job() -> ProcessingJob {
+    ProcessingJob {
+        video_id: "video-1".to_string(),
+        r2_key: "videos/video-1.mp4".to_string(),
+        refingerprint: false,
+        attempts: 0,
+    }
+}
+
+fn policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 4,
+        base_delay_seconds: 10,
+        max_delay_seconds: 60,
+        processing_timeout_seconds: 3600,
+    }
+}
+
+/// Delivers `job` the way the queue would, re-delivering on retry, and returns
+/// every action taken.
+fn deliver(processor: &ScriptedProcessor, dead_letters: &LocalQueue) -> Vec<Action> {
+    let mut actions = Vec::new();
+    for attempt in 1.. {
+        let action = block_on(handle(processor, dead_letters, &job(), attempt, &policy())).unwrap();
+        actions.push(action.clone());
+        if !matches!(action, Action::Retry { .. }) {
+            break;
+        }
+    }
+    actions
+}
+
+#[test]
+fn test_backoff_is_exponential_and_capped() {
+    let policy = policy();
+    assert_eq!(policy.backoff(1), 10);
+    assert_eq!(policy.backoff(2), 20);
+    assert_eq!(policy.backoff(3), 40);
+    assert_eq!(policy.backoff(4), 60);
+    assert_eq!(policy.backoff(40), 60);
+}
+
+#[test]
+fn test_outcome_from_status() {
+    assert_eq!(
+        Outcome::from_status(202, None, String::new()),
+        Outcome::Done
+    );
+    assert_eq!(
+        Outcome::from_status(409, None, String::new()),
+        Outcome::Done
+    );
+    assert_eq!(
+        Outcome::from_status(503, Some(30), String::new()),
+        Outcome::Retry {
+            retry_after: Some(30)
+        }
+    );
+    assert!(matches!(
+        Outcome::from_status(400, None, "bad".to_string()),
+        Outcome::Permanent(_)
+    ));
+}
+
+#[test]
+fn test_retries_then_succeeds() {
+    let processor = ScriptedProcessor::new(vec![
+        Outcome::Retry { retry_after: None },
+        Outcome::Retry {
+            retry_after: Some(45),
+        },
+    ]);
+    let dead_letters = LocalQueue::default();
+
+    let actions = deliver(&processor, &dead_letters);
+    assert_eq!(
+        actions,
+        vec![
+            Action::Retry { delay_seconds: 10 },
+            Action::Retry { delay_seconds: 45 },
+            Action::Ack,
+        ]
+    );
+    assert_eq!(*processor.calls.borrow(), 3);
+    assert!(dead_letters.sent.borrow().is_empty());
+}
+
+#[test]
+fn test_dead_letters_after_max_attempts() {
+    let processor = ScriptedProcessor::new(vec![Outcome::Retry { retry_after: None }; 10]);
+    let dead_letters = LocalQueue::default();
+
+    let actions = deliver(&processor, &dead_letters);
+    assert_eq!(actions.len(), 4);
+    assert!(matches!(actions[3], Action::DeadLetter { .. }));
+    assert_eq!(*dead_letters.sent.borrow(), vec![job()]);
+}
+
+#[test]
+fn test_permanent_failure_is_not_retried() {
+    let processor = ScriptedProcessor::new(vec![Outcome::Permanent("bad request".to_string())]);
+    let dead_letters = LocalQueue::default();
+
+    let actions = deliver(&processor, &dead_letters);
+    assert_eq!(
+        actions,
+        vec![Action::DeadLetter {
+            reason: "bad request".to_string()
+        }]
+    );
+    assert_eq!(dead_letters.sent.borrow().len(), 1);
+}
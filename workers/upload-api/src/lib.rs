@@ -1,17 +1,109 @@
 use worker::*;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+mod config;
+mod dispatch;
+mod matcher;
+mod policy;
+mod report;
+mod videos;
+
+use config::{FingerprintVersions, MatchConfig};
+use dispatch::{Action, Outcome, ProcessingJob, RetryPolicy};
+use policy::{PolicyConfig, VideoStatus};
+use shared::audio_hash::AudioHashKey;
 
 #[derive(Deserialize, Serialize)]
 struct AudioHash {
+    /// Packed as laid out in `shared::audio_hash`; always below 2^52, so it is
+    /// bound to D1 as a double rather than a BigInt.
     hash: u64,
     time_offset: u32,
 }
 
+#[derive(Deserialize, Serialize)]
+struct FrameHash {
+    hash: String,
+    /// Presentation time of the frame in the video.
+    timestamp_ms: u64,
+    /// Hash of the horizontally flipped frame. Only used to look up mirrored
+    /// copies, never stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mirrored: Option<String>,
+    /// Hashes of regions of the frame (grid cells, then the center), `None`
+    /// for regions without detail. Only used to look up videos shown
+    /// picture-in-picture, never stored.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    regions: Vec<Option<String>>,
+}
+
 #[derive(Deserialize, Serialize)]
 struct CompleteRequest {
     video_id: String,
-    hashes: Vec<String>,
+    hashes: Vec<FrameHash>,
+    /// Algorithm and size that produced `hashes`, e.g. `dhash-8`.
+    #[serde(default = "default_hash_algorithm")]
+    hash_algorithm: String,
+    /// Processor code versions behind `hashes` and `audio_hashes`; only
+    /// hashes of the same version are compared.
+    #[serde(default = "default_fingerprint_version")]
+    visual_fingerprint_version: u32,
     audio_hashes: Vec<AudioHash>,
+    #[serde(default = "default_fingerprint_version")]
+    audio_fingerprint_version: u32,
+    /// Typical spacing of `hashes` in seconds, used as the alignment tolerance.
+    #[serde(default = "default_frame_interval")]
+    frame_interval: f64,
+    /// Seconds covered by one `AudioHash::time_offset` step.
+    #[serde(default = "default_audio_offset_seconds")]
+    audio_offset_seconds: f64,
+    /// Set for backfill jobs: replace the stored hashes and leave the status
+    /// and match history alone.
+    #[serde(default)]
+    refingerprint: bool,
+    /// Problems the processor worked around, e.g. audio it could not
+    /// fingerprint.
+    #[serde(default)]
+    warnings: Vec<ProcessingWarning>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ProcessingWarning {
+    stage: String,
+    kind: String,
+    message: String,
+}
+
+fn default_hash_algorithm() -> String {
+    "dhash-8".to_string()
+}
+
+fn default_fingerprint_version() -> u32 {
+    1
+}
+
+fn default_frame_interval() -> f64 {
+    1.0
+}
+
+fn default_audio_offset_seconds() -> f64 {
+    2048.0 / 44100.0
+}
+
+#[derive(Deserialize)]
+struct FailedRequest {
+    video_id: String,
+    error: String,
+    /// The job may succeed if run again, e.g. R2 was unreachable. The video
+    /// is sent back to the processor until the retry policy gives up.
+    #[serde(default)]
+    retryable: bool,
+}
+
+#[derive(Deserialize)]
+struct ReviewRequest {
+    status: VideoStatus,
 }
 
 #[event(fetch)]
@@ -27,21 +119,32 @@ pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
                 return Response::error("File is empty", 400);
             }
 
+            let user_id = req
+                .url()?
+                .query_pairs()
+                .find(|(k, _)| k == "user_id")
+                .map(|(_, v)| v.into_owned())
+                .unwrap_or_else(|| "web-user".to_string());
+
             let id = uuid::Uuid::new_v4().to_string();
             let key = format!("videos/{}.mp4", id);
             bucket.put(key.clone(), bytes).execute().await?;
 
             let db = ctx.env.d1("DB")?;
-            let statement = db.prepare("INSERT INTO videos (id, r2_key, user_id, status, uploaded_at) VALUES (?, ?, ?, ?, ?)");
+            let statement = db.prepare("INSERT INTO videos (id, r2_key, user_id, status, uploaded_at, processing_started_at) VALUES (?, ?, ?, ?, ?, strftime('%s', 'now'))");
             let query = statement.bind(&[
                 id.clone().into(),
                 key.clone().into(),
-                "web-user".into(), 
+                user_id.into(),
                 "processing".into(),
                 worker::Date::now().to_string().into()
             ])?;
             query.run().await?;
 
+            // Should this fail, the stuck-video sweep sends the job later.
+            let queue = ctx.env.queue("VIDEO_QUEUE")?;
+            queue.send(&ProcessingJob { video_id: id.clone(), r2_key: key, refingerprint: false, attempts: 0 }).await?;
+
             console_log!("Video uploaded! ID: {}", id);
             Response::ok(format!("Uploaded video: {}", id))
         })
@@ -52,86 +155,472 @@ pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
             }
             let body = body.unwrap();
             let db = ctx.env.d1("DB")?;
-
-            let mut duplicate_id: Option<String> = None;
-            
-            for hash in body.hashes.iter().take(5) {
-                let stmt = db.prepare("SELECT video_id FROM video_hashes WHERE hash_value = ? LIMIT 1");
-                let query = stmt.bind(&[hash.clone().into()])?;
-                let result = query.first::<String>(Some("video_id")).await;
-                if let Ok(Some(vid)) = result {
-                    if vid != body.video_id {
-                         duplicate_id = Some(vid);
-                         break;
-                    }
-                }
+            for warning in &body.warnings {
+                console_log!(
+                    "Processor warning for {} during {}: {} ({})",
+                    body.video_id,
+                    warning.stage,
+                    warning.message,
+                    warning.kind
+                );
             }
-            
-            if duplicate_id.is_none() {
-                 for hash in body.audio_hashes.iter().take(20) {
-                    let val = hash.hash as i64;
-                    
-                    let stmt = db.prepare("SELECT video_id FROM audio_hashes WHERE hash = ? LIMIT 1");
-                    let query = stmt.bind(&[val.into()])?;
-                    let result = query.first::<String>(Some("video_id")).await;
-                    if let Ok(Some(vid)) = result {
-                        if vid != body.video_id {
-                             duplicate_id = Some(vid);
-                             break;
-                        }
-                    }
-                 }
+            if let Some(e) = body
+                .audio_hashes
+                .iter()
+                .find_map(|h| AudioHashKey::decode(h.hash).err())
+            {
+                return Response::error(format!("Bad Request: {}", e), 400);
             }
-            
-            if let Some(orig_id) = duplicate_id {
-                db.prepare("UPDATE videos SET status = 'duplicate', original_video_id = ? WHERE id = ?")
-                    .bind(&[orig_id.clone().into(), body.video_id.clone().into()])?
-                    .run().await?;
-                
-                return Response::error(format!("Duplicate of {}", orig_id), 409);
+            let malformed = body.hashes.iter().find_map(|frame| {
+                std::iter::once(&frame.hash)
+                    .chain(&frame.mirrored)
+                    .chain(frame.regions.iter().flatten())
+                    .find(|hash| matcher::visual::decode_hex(hash).is_none())
+            });
+            if let Some(hash) = malformed {
+                return Response::error(format!("Bad Request: frame hash {:?} is not hex", hash), 400);
+            }
+            // The matcher divides frame times by it.
+            if !(body.frame_interval > 0.0 && body.frame_interval.is_finite()) {
+                return Response::error(
+                    format!("Bad Request: frame_interval must be positive, got {}", body.frame_interval),
+                    400,
+                );
             }
 
-            let mut statements = Vec::new();
-            
-            statements.push(db.prepare("UPDATE videos SET status = 'active' WHERE id = ?").bind(&[body.video_id.clone().into()])?);
+            if body.refingerprint {
+                return refingerprint(&db, &body).await;
+            }
+
+            let Some(video) = videos::get(&db, &body.video_id).await? else {
+                return Response::error("Video not found", 404);
+            };
+            let config = MatchConfig::from_env(&ctx.env);
+            let policy = PolicyConfig::from_env(&ctx.env);
 
-            for (i, hash) in body.hashes.iter().enumerate() {
-                statements.push(
-                    db.prepare("INSERT INTO video_hashes (video_id, frame_index, hash_value) VALUES (?, ?, ?)")
-                      .bind(&[body.video_id.clone().into(), (i as i32).into(), hash.clone().into()])?
+            // A video a moderator unblocked would only match its original
+            // again, so it is indexed without being matched.
+            let approved = video.approved_at.is_some();
+            let report = if approved {
+                console_log!("Indexing {} as approved by a moderator", body.video_id);
+                None
+            } else {
+                matcher::find_match(&db, &body, &config, &policy).await?
+            };
+            let status = policy::processed_status(approved, report.as_ref().map(|r| r.decision));
+
+            if let Some(report) = &report {
+                console_log!(
+                    "Best candidate {} for {}: visual {:.2}, audio {:.2}, confidence {:.2} -> {}",
+                    report.candidate_video_id,
+                    body.video_id,
+                    report.visual_similarity,
+                    report.audio_similarity,
+                    report.confidence,
+                    report.decision
                 );
-                
-                if hash.len() == 16 {
-                    for b in 0..4 {
-                        let start = b * 4;
-                        let end = start + 4;
-                        if let Ok(val) = u16::from_str_radix(&hash[start..end], 16) {
-                             statements.push(
-                                db.prepare("INSERT INTO video_lsh_bands (video_id, band_index, band_value) VALUES (?, ?, ?)")
-                                  .bind(&[body.video_id.clone().into(), (b as i32).into(), (val as i32).into()])?
-                             );
-                        }
-                    }
-                }
             }
-            
-            for hash in body.audio_hashes.iter() {
-                statements.push(
-                    db.prepare("INSERT INTO audio_hashes (video_id, hash, time_offset) VALUES (?, ?, ?)")
-                      .bind(&[
-                          body.video_id.clone().into(), 
-                          (hash.hash as i64).into(), 
-                          (hash.time_offset as i32).into()
-                      ])?
-                );
+
+            let original_id = report
+                .as_ref()
+                .filter(|r| r.decision != VideoStatus::Active)
+                .map(|r| r.candidate_video_id.as_str());
+            if let Err(e) = policy::transition(&db, &body.video_id, status, original_id).await {
+                return Response::error(e.to_string(), e.status_code());
             }
-            
+            // Only record the report once the decision it backs has been applied.
+            if let Some(report) = &report {
+                report.insert(&db)?.run().await?;
+            }
+            db.prepare("UPDATE videos SET processed_at = ? WHERE id = ?")
+                .bind(&[worker::Date::now().to_string().into(), body.video_id.clone().into()])?
+                .run().await?;
+
+            if let Some(report) = report.as_ref().filter(|r| r.decision == VideoStatus::Blocked) {
+                return Ok(Response::from_json(report)?.with_status(409));
+            }
+
+            let statements = fingerprint_statements(&db, &body)?;
             for chunk in statements.chunks(100) {
                  db.batch(chunk.to_vec()).await?;
             }
 
-            Response::ok("Video processed and indexed")
+            let response = Response::from_json(&json!({
+                "video_id": body.video_id,
+                "status": status,
+                "match": report
+            }))?;
+            match status {
+                VideoStatus::NeedsReview => Ok(response.with_status(202)),
+                _ => Ok(response),
+            }
+        })
+        .post_async("/internal/failed", |mut req, ctx| async move {
+            let body: FailedRequest = match req.json().await {
+                Ok(body) => body,
+                Err(e) => return Response::error(format!("Bad Request: {}", e), 400),
+            };
+            let db = ctx.env.d1("DB")?;
+
+            console_log!("Processing failed for {}: {}", body.video_id, body.error);
+            if body.retryable {
+                let Some(video) = videos::pending(&db, &body.video_id).await? else {
+                    return Response::error("Video is not processing", 409);
+                };
+                let retry = RetryPolicy::from_env(&ctx.env);
+                return match retry_processing(&db, &ctx.env, &video, &retry).await? {
+                    Action::Retry { delay_seconds } => {
+                        Response::ok(format!("Video queued again in {}s", delay_seconds))
+                    }
+                    Action::DeadLetter { .. } => Response::ok("Video marked as failed"),
+                    Action::Ack => Response::error("Video is no longer processing", 409),
+                };
+            }
+            if let Err(e) = policy::transition(&db, &body.video_id, VideoStatus::Failed, None).await {
+                return Response::error(e.to_string(), e.status_code());
+            }
+            Response::ok("Video marked as failed")
+        })
+        .get_async("/videos", |req, ctx| async move {
+            let mut query = videos::ListQuery {
+                limit: 20,
+                ..Default::default()
+            };
+            for (key, value) in req.url()?.query_pairs() {
+                match key.as_ref() {
+                    "user_id" => query.user_id = Some(value.into_owned()),
+                    "status" => match value.parse::<VideoStatus>() {
+                        Ok(status) => query.status = Some(status.as_str().to_string()),
+                        Err(e) => return Response::error(e, 400),
+                    },
+                    "limit" => match value.parse::<usize>() {
+                        Ok(limit) => query.limit = limit.clamp(1, 100),
+                        Err(_) => return Response::error("Invalid limit", 400),
+                    },
+                    "cursor" => match videos::Cursor::parse(&value) {
+                        Some(cursor) => query.cursor = Some(cursor),
+                        None => return Response::error("Invalid cursor", 400),
+                    },
+                    _ => {}
+                }
+            }
+
+            let db = ctx.env.d1("DB")?;
+            let (videos, next_cursor) = videos::list(&db, &query).await?;
+            Response::from_json(&json!({
+                "videos": videos,
+                "next_cursor": next_cursor
+            }))
+        })
+        .get_async("/videos/:id", |_req, ctx| async move {
+            let Some(id) = ctx.param("id").cloned() else {
+                return Response::error("Missing video id", 400);
+            };
+            let db = ctx.env.d1("DB")?;
+
+            match videos::get(&db, &id).await? {
+                Some(video) => Response::from_json(&video),
+                None => Response::error("Video not found", 404),
+            }
+        })
+        .delete_async("/videos/:id", |_req, ctx| async move {
+            let Some(id) = ctx.param("id").cloned() else {
+                return Response::error("Missing video id", 400);
+            };
+            let db = ctx.env.d1("DB")?;
+            let bucket = ctx.env.bucket("VIDEO_BUCKET")?;
+
+            let Some(video) = videos::get(&db, &id).await? else {
+                return Response::error("Video not found", 404);
+            };
+            let queue = ctx.env.queue("VIDEO_QUEUE")?;
+            let reevaluated = videos::delete(&db, &bucket, &queue, &video).await?;
+
+            console_log!("Video {} deleted, {} videos to re-evaluate", id, reevaluated.len());
+            Response::from_json(&json!({
+                "video_id": id,
+                "deleted": true,
+                "reevaluated": reevaluated
+            }))
+        })
+        .post_async("/videos/:id/review", |mut req, ctx| async move {
+            let Some(id) = ctx.param("id").cloned() else {
+                return Response::error("Missing video id", 400);
+            };
+            let review: ReviewRequest = match req.json().await {
+                Ok(review) => review,
+                Err(e) => return Response::error(format!("Bad Request: {}", e), 400),
+            };
+            if review.status == VideoStatus::Processing {
+                return Response::error("Bad Request: processing is not a review decision", 400);
+            }
+            let db = ctx.env.d1("DB")?;
+
+            let Some(video) = videos::get(&db, &id).await? else {
+                return Response::error("Video not found", 404);
+            };
+            let current: VideoStatus = video.status.parse().map_err(Error::RustError)?;
+            let status = policy::review_status(current, review.status);
+            if let Err(e) = policy::transition(&db, &id, status, None).await {
+                return Response::error(e.to_string(), e.status_code());
+            }
+            // Only unblocking approves a video; any other decision withdraws an
+            // earlier approval, so a later re-evaluation matches it again.
+            let approved_at = (status == VideoStatus::Processing).then(|| worker::Date::now().to_string());
+            db.prepare("UPDATE videos SET approved_at = ? WHERE id = ?")
+                .bind(&[approved_at.into(), id.clone().into()])?
+                .run().await?;
+
+            if status == VideoStatus::Processing {
+                ctx.env
+                    .queue("VIDEO_QUEUE")?
+                    .send(&ProcessingJob {
+                        video_id: id.clone(),
+                        r2_key: video.r2_key,
+                        refingerprint: false,
+                        attempts: 0,
+                    })
+                    .await?;
+            } else if !status.is_indexed() {
+                // Blocked videos must not be matched against, so their
+                // fingerprints leave the index.
+                db.batch(videos::delete_fingerprints(&db, &id)?).await?;
+            }
+
+            console_log!("Video {} reviewed: {}", id, status);
+            Response::from_json(&json!({ "video_id": id, "status": status }))
+        })
+        .post_async("/internal/backfill", |req, ctx| async move {
+            let mut limit = 50;
+            let mut cursor = None;
+            for (key, value) in req.url()?.query_pairs() {
+                match key.as_ref() {
+                    "limit" => match value.parse::<usize>() {
+                        Ok(l) => limit = l.clamp(1, 100),
+                        Err(_) => return Response::error("Invalid limit", 400),
+                    },
+                    "cursor" => match videos::Cursor::parse(&value) {
+                        Some(c) => cursor = Some(c),
+                        None => return Response::error("Invalid cursor", 400),
+                    },
+                    _ => {}
+                }
+            }
+
+            let db = ctx.env.d1("DB")?;
+            let versions = FingerprintVersions::from_env(&ctx.env);
+            let (outdated, next_cursor) =
+                videos::outdated(&db, &versions, cursor.as_ref(), limit).await?;
+
+            let queue = ctx.env.queue("VIDEO_QUEUE")?;
+            for video in &outdated {
+                queue
+                    .send(&ProcessingJob {
+                        video_id: video.id.clone(),
+                        r2_key: video.r2_key.clone(),
+                        refingerprint: true,
+                        attempts: 0,
+                    })
+                    .await?;
+            }
+
+            console_log!("Queued {} videos for re-fingerprinting", outdated.len());
+            let enqueued: Vec<&str> = outdated.iter().map(|v| v.id.as_str()).collect();
+            Response::from_json(&json!({
+                "enqueued": enqueued,
+                "next_cursor": next_cursor
+            }))
         })
         .run(req, env)
         .await
 }
+
+/// Statements inserting the upload's frame hashes, their LSH bands and its
+/// audio hashes, each tagged with the version that produced it.
+fn fingerprint_statements(
+    db: &D1Database,
+    body: &CompleteRequest,
+) -> Result<Vec<D1PreparedStatement>> {
+    let mut statements = Vec::new();
+
+    for (i, frame) in body.hashes.iter().enumerate() {
+        statements.push(
+            db.prepare(
+                "INSERT INTO video_hashes \
+                 (video_id, frame_index, hash_value, timestamp_ms, hash_algorithm, fingerprint_version) \
+                 VALUES (?, ?, ?, ?, ?, ?)",
+            )
+            .bind(&[
+                body.video_id.clone().into(),
+                (i as i32).into(),
+                frame.hash.clone().into(),
+                (frame.timestamp_ms as f64).into(),
+                body.hash_algorithm.clone().into(),
+                body.visual_fingerprint_version.into(),
+            ])?,
+        );
+
+        for (band_index, band_value) in matcher::visual::lsh_bands(&frame.hash) {
+            statements.push(
+                db.prepare(
+                    "INSERT INTO video_lsh_bands \
+                     (video_id, band_index, band_value, hash_algorithm, fingerprint_version) \
+                     VALUES (?, ?, ?, ?, ?)",
+                )
+                .bind(&[
+                    body.video_id.clone().into(),
+                    band_index.into(),
+                    band_value.into(),
+                    body.hash_algorithm.clone().into(),
+                    body.visual_fingerprint_version.into(),
+                ])?,
+            );
+        }
+    }
+
+    for hash in body.audio_hashes.iter() {
+        statements.push(
+            db.prepare(
+                "INSERT INTO audio_hashes (video_id, hash, time_offset, fingerprint_version) \
+                 VALUES (?, ?, ?, ?)",
+            )
+            .bind(&[
+                body.video_id.clone().into(),
+                (hash.hash as f64).into(),
+                (hash.time_offset as i32).into(),
+                body.audio_fingerprint_version.into(),
+            ])?,
+        );
+    }
+
+    Ok(statements)
+}
+
+/// Replaces a backfilled video's hashes with the newly computed ones. Videos
+/// blocked since the backfill queued them stay out of the index.
+async fn refingerprint(db: &D1Database, body: &CompleteRequest) -> Result<Response> {
+    let Some(video) = videos::get(db, &body.video_id).await? else {
+        return Response::error("Video not found", 404);
+    };
+    let indexed = video
+        .status
+        .parse::<VideoStatus>()
+        .is_ok_and(|status| status.is_indexed());
+
+    if indexed {
+        let mut statements = videos::delete_fingerprints(db, &body.video_id)?;
+        statements.extend(fingerprint_statements(db, body)?);
+        for chunk in statements.chunks(100) {
+            db.batch(chunk.to_vec()).await?;
+        }
+        console_log!("Re-fingerprinted video {}", body.video_id);
+    }
+
+    Response::from_json(&json!({
+        "video_id": body.video_id,
+        "status": video.status,
+        "refingerprinted": indexed
+    }))
+}
+
+/// Sends a video still in `processing` to the processor again, after its job
+/// failed transiently or went missing, or marks it failed and dead-letters
+/// its job once `retry` gives up. Returns `Ack` when the video has left
+/// `processing` in the meantime.
+async fn retry_processing(
+    db: &D1Database,
+    env: &Env,
+    video: &videos::Pending,
+    retry: &RetryPolicy,
+) -> Result<Action> {
+    let job = ProcessingJob {
+        video_id: video.id.clone(),
+        r2_key: video.r2_key.clone(),
+        refingerprint: false,
+        attempts: 0,
+    };
+    let attempt = video.processing_attempts + 1;
+    let action = retry.decide(Outcome::Retry { retry_after: None }, attempt);
+    match &action {
+        Action::Retry { delay_seconds } => {
+            if !videos::restart_processing(db, video).await? {
+                return Ok(Action::Ack);
+            }
+            console_log!("Re-queueing video {} in {}s (attempt {})", video.id, delay_seconds, attempt);
+            env.queue("VIDEO_QUEUE")?
+                .send(MessageBuilder::new(job).delay_seconds(*delay_seconds).build())
+                .await?;
+        }
+        Action::DeadLetter { reason } => {
+            console_log!("Giving up on video {}: {}", video.id, reason);
+            if let Err(e) = policy::transition(db, &video.id, VideoStatus::Failed, None).await {
+                console_log!("Could not mark video {} as failed: {}", video.id, e);
+                return Ok(Action::Ack);
+            }
+            env.queue("DEAD_LETTER_QUEUE")?.send(&job).await?;
+        }
+        Action::Ack => {}
+    }
+    Ok(action)
+}
+
+/// Re-queues videos that have been `processing` for longer than
+/// `PROCESSING_TIMEOUT`: their job was lost, e.g. to a processor restart, or
+/// its failure was never reported.
+#[event(scheduled)]
+pub async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    let result: Result<usize> = async {
+        let db = env.d1("DB")?;
+        let retry = RetryPolicy::from_env(&env);
+        let now = (Date::now().as_millis() / 1000) as i64;
+        let stuck = videos::stuck(&db, now - retry.processing_timeout_seconds as i64, 100).await?;
+        for video in &stuck {
+            retry_processing(&db, &env, video, &retry).await?;
+        }
+        Ok(stuck.len())
+    }
+    .await;
+    match result {
+        Ok(count) => console_log!("Swept {} stuck videos", count),
+        Err(e) => console_log!("Stuck video sweep failed: {}", e),
+    }
+}
+
+/// Consumes `VIDEO_QUEUE` and hands each job to the processor, retrying with
+/// backoff and dead-lettering jobs that keep failing.
+#[event(queue)]
+pub async fn queue(batch: MessageBatch<ProcessingJob>, env: Env, _ctx: Context) -> Result<()> {
+    let processor = dispatch::HttpProcessor::from_env(&env);
+    let queue = env.queue("VIDEO_QUEUE")?;
+    let dead_letters = env.queue("DEAD_LETTER_QUEUE")?;
+    let retry = dispatch::RetryPolicy::from_env(&env);
+    let db = env.d1("DB")?;
+
+    for message in batch.messages()? {
+        let job = message.body();
+        let attempt = job.attempts + 1;
+        match dispatch::handle(&processor, &dead_letters, job, attempt, &retry).await? {
+            Action::Ack => message.ack(),
+            Action::Retry { delay_seconds } => {
+                console_log!("Retrying video {} in {}s", job.video_id, delay_seconds);
+                let retried = ProcessingJob { attempts: attempt, ..job.clone() };
+                queue
+                    .send(MessageBuilder::new(retried).delay_seconds(delay_seconds).build())
+                    .await?;
+                message.ack();
+            }
+            Action::DeadLetter { reason } => {
+                console_log!("Dead-lettered video {}: {}", job.video_id, reason);
+                // A failed backfill leaves the old, still valid, hashes in place.
+                if !job.refingerprint {
+                    if let Err(e) = policy::transition(&db, &job.video_id, VideoStatus::Failed, None).await {
+                        console_log!("Could not mark video {} as failed: {}", job.video_id, e);
+                    }
+                }
+                message.ack();
+            }
+        }
+    }
+
+    Ok(())
+}
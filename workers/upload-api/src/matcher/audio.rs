@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+/// One row from `audio_hashes` whose hash also occurs in the query.
+#[derive(Debug, Clone)]
+pub struct AudioHit {
+    pub video_id: String,
+    pub hash: i64,
+    pub time_offset: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioScore {
+    pub video_id: String,
+    /// Stored offset minus query offset at the start of the winning bin.
+    pub offset_delta: i64,
+    /// Hits in the winning bin and its immediate neighbours.
+    pub votes: usize,
+    /// Hits in the best bin outside the winning window.
+    pub runner_up: usize,
+    /// First and last query offset that voted for the winning bin.
+    pub query_start: i64,
+    pub query_end: i64,
+}
+
+impl AudioScore {
+    /// A match needs enough aligned hits, and those hits must dominate every
+    /// other alignment for the same video. Random collisions spread across many
+    /// deltas, while copied audio piles up in a single one.
+    pub fn is_match(&self, min_votes: usize, peak_ratio: f64) -> bool {
+        self.votes >= min_votes && self.votes as f64 >= peak_ratio * self.runner_up.max(1) as f64
+    }
+}
+
+/// Shazam-style offset voting. `query` maps each query hash to the offsets
+/// where it occurs. Every hit votes for `(video_id, stored - query)`, grouped
+/// into bins `bin_width` deltas wide, and each candidate is scored by its
+/// strongest bin.
+pub fn vote(query: &HashMap<i64, Vec<i64>>, hits: &[AudioHit], bin_width: i64) -> Vec<AudioScore> {
+    let bin_width = bin_width.max(1);
+    // Per video, each delta bin keeps the query offsets that voted for it.
+    let mut histograms: HashMap<&str, HashMap<i64, Vec<i64>>> = HashMap::new();
+
+    for hit in hits {
+        let Some(offsets) = query.get(&hit.hash) else {
+            continue;
+        };
+        let histogram = histograms.entry(hit.video_id.as_str()).or_default();
+        for offset in offsets {
+            histogram
+                .entry((hit.time_offset - offset).div_euclid(bin_width))
+                .or_default()
+                .push(*offset);
+        }
+    }
+
+    let mut scores: Vec<AudioScore> = histograms
+        .into_iter()
+        .map(|(video_id, histogram)| {
+            let mut score = score_histogram(video_id, &histogram);
+            score.offset_delta *= bin_width;
+            score
+        })
+        .collect();
+    scores.sort_by_key(|s| std::cmp::Reverse(s.votes));
+    scores
+}
+
+fn score_histogram(video_id: &str, histogram: &HashMap<i64, Vec<i64>>) -> AudioScore {
+    // Peak times jitter by one hop when the copy is not hop-aligned with the
+    // original, so neighbouring bins count towards the same alignment.
+    let bins = |delta: i64| (delta - 1..=delta + 1).filter_map(|d| histogram.get(&d));
+    let window = |delta: i64| -> usize { bins(delta).map(Vec::len).sum() };
+
+    let (offset_delta, votes) = histogram
+        .keys()
+        .map(|&delta| (delta, window(delta)))
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+        .unwrap_or((0, 0));
+
+    let runner_up = histogram
+        .keys()
+        .filter(|&&delta| (delta - offset_delta).abs() > 2)
+        .map(|&delta| window(delta))
+        .max()
+        .unwrap_or(0);
+
+    let offsets = || bins(offset_delta).flatten().copied();
+
+    AudioScore {
+        video_id: video_id.to_string(),
+        offset_delta,
+        votes,
+        runner_up,
+        query_start: offsets().min().unwrap_or(0),
+        query_end: offsets().max().unwrap_or(0),
+    }
+}
@@ -0,0 +1,447 @@
+pub mod audio;
+pub mod visual;
+
+use crate::config::MatchConfig;
+use crate::policy::PolicyConfig;
+use crate::report::MatchReport;
+use crate::{AudioHash, CompleteRequest, FrameHash};
+use serde::Deserialize;
+use shared::audio_hash::AudioHashKey;
+use std::collections::HashMap;
+use worker::wasm_bindgen::JsValue;
+use worker::*;
+
+/// Which hashes of the upload matched the candidate's full frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryView {
+    Full,
+    /// The upload is a horizontally flipped copy of the candidate.
+    Mirrored,
+    /// The candidate fills one region of the upload (picture-in-picture, or
+    /// framed by an overlay); the index follows `FrameHash::regions`.
+    Region(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct VisualMatch {
+    pub video_id: String,
+    pub view: QueryView,
+    pub score: visual::VisualScore,
+    pub segment: Option<visual::Segment>,
+    /// Share of the query found in the candidate, or the density of aligned
+    /// frames inside the matched segment for excerpts, whichever is higher.
+    pub similarity: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioMatch {
+    pub score: audio::AudioScore,
+    pub segment: visual::Segment,
+    /// Share of the sampled query hashes inside the matched segment that voted
+    /// for the winning alignment.
+    pub similarity: f64,
+}
+
+#[derive(Deserialize)]
+struct CandidateRow {
+    video_id: String,
+}
+
+#[derive(Deserialize)]
+struct FrameRow {
+    timestamp_ms: i64,
+    hash_value: String,
+}
+
+#[derive(Deserialize)]
+struct AudioHitRow {
+    video_id: String,
+    hash: i64,
+    time_offset: i64,
+}
+
+/// D1 allows at most 100 bound parameters per statement.
+const MAX_BOUND_PARAMS: usize = 90;
+
+/// Sampling intervals a run may skip before it is split. Scene-change and
+/// keyframe sampling space frames several seconds apart, so the configured
+/// gap alone would split every run of such an upload.
+const MAX_GAP_INTERVALS: f64 = 3.0;
+
+/// Scores the upload against every stored video it shares visual or audio
+/// hashes with and reports the candidate with the highest combined confidence.
+pub async fn find_match(
+    db: &D1Database,
+    body: &CompleteRequest,
+    config: &MatchConfig,
+    policy: &PolicyConfig,
+) -> Result<Option<MatchReport>> {
+    let visual = find_visual_matches(
+        db,
+        &body.video_id,
+        &body.hashes,
+        &body.hash_algorithm,
+        body.visual_fingerprint_version,
+        body.frame_interval,
+        config,
+    )
+    .await?;
+    let audio = find_audio_matches(
+        db,
+        &body.video_id,
+        &body.audio_hashes,
+        body.audio_fingerprint_version,
+        body.audio_offset_seconds,
+        config,
+    )
+    .await?;
+
+    let mut candidates: Vec<&str> = visual
+        .iter()
+        .map(|m| m.video_id.as_str())
+        .chain(audio.iter().map(|m| m.score.video_id.as_str()))
+        .collect();
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    let reports = candidates.into_iter().map(|candidate_id| {
+        let v = visual.iter().find(|m| m.video_id == candidate_id);
+        let a = audio.iter().find(|m| m.score.video_id == candidate_id);
+        match v.map(|m| m.view) {
+            Some(QueryView::Mirrored) => console_log!(
+                "{} matches a mirrored copy of {}",
+                body.video_id,
+                candidate_id
+            ),
+            Some(QueryView::Region(region)) => console_log!(
+                "{} shows {} inside region {}",
+                body.video_id,
+                candidate_id,
+                region
+            ),
+            _ => {}
+        }
+        MatchReport::new(
+            &body.video_id,
+            candidate_id,
+            v.map_or(0.0, |m| m.similarity),
+            a.map_or(0.0, |m| m.similarity),
+            v.map_or(0, |m| m.score.matched_frames),
+            policy,
+            v.and_then(|m| m.segment.clone())
+                .or_else(|| a.map(|m| m.segment.clone())),
+        )
+    });
+
+    Ok(reports.max_by(|a, b| a.confidence.total_cmp(&b.confidence)))
+}
+
+/// Gathers candidates from `video_lsh_bands`, then scores each of them by
+/// Hamming distance over every stored frame. A candidate matches when enough
+/// of the query's frames are found in it, or when a contiguous aligned
+/// segment is long enough to count as an excerpt. Frames are placed by their
+/// timestamps; `frame_interval`, the typical spacing between sampled frames,
+/// only sets the alignment tolerance. Only hashes produced by the same
+/// `hash_algorithm` and `fingerprint_version` are compared. Mirrored and
+/// region hashes are looked up and scored against the same full-frame
+/// references as the upload's own frames; regions only by their time-aligned
+/// run. Hashes of near-uniform pictures are left out on both sides.
+pub async fn find_visual_matches(
+    db: &D1Database,
+    video_id: &str,
+    hashes: &[FrameHash],
+    hash_algorithm: &str,
+    fingerprint_version: u32,
+    frame_interval: f64,
+    config: &MatchConfig,
+) -> Result<Vec<VisualMatch>> {
+    // All of the upload's hashes come from one hasher, so one length.
+    let threshold = hashes
+        .iter()
+        .find_map(|f| visual::decode_hex(&f.hash))
+        .map_or(0, |hash| config.hamming_threshold(hash.len() * 8));
+
+    let mut statements = Vec::new();
+    for frame in hashes {
+        let bands: Vec<(i32, i32)> = std::iter::once(&frame.hash)
+            .chain(&frame.mirrored)
+            .chain(frame.regions.iter().flatten())
+            .filter(|hash| {
+                visual::decode_hex(hash).is_some_and(|h| visual::is_informative(&h, threshold))
+            })
+            .flat_map(|hash| visual::lsh_bands(hash))
+            .collect();
+        for chunk in bands.chunks((MAX_BOUND_PARAMS - 2) / 2) {
+            let clause = vec!["(band_index = ? AND band_value = ?)"; chunk.len()].join(" OR ");
+            let params: Vec<JsValue> = [
+                JsValue::from(hash_algorithm),
+                JsValue::from(fingerprint_version),
+            ]
+            .into_iter()
+            .chain(
+                chunk
+                    .iter()
+                    .flat_map(|(index, value)| [JsValue::from(*index), JsValue::from(*value)]),
+            )
+            .collect();
+            statements.push(
+                db.prepare(format!(
+                    "SELECT DISTINCT video_id FROM video_lsh_bands \
+                     WHERE hash_algorithm = ? AND fingerprint_version = ? AND ({})",
+                    clause
+                ))
+                .bind(&params)?,
+            );
+        }
+    }
+
+    // Count how many query frames hit each candidate so the most promising
+    // ones are scored first.
+    let mut hits: HashMap<String, usize> = HashMap::new();
+    for chunk in statements.chunks(100) {
+        for result in db.batch(chunk.to_vec()).await? {
+            for row in result.results::<CandidateRow>()? {
+                if row.video_id != video_id {
+                    *hits.entry(row.video_id).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    let mut candidates: Vec<(String, usize)> = hits.into_iter().collect();
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.1));
+    candidates.truncate(config.max_candidates);
+
+    let regions = hashes.iter().map(|f| f.regions.len()).max().unwrap_or(0);
+    let mut views = vec![
+        (
+            QueryView::Full,
+            query_frames(hashes, threshold, |f| Some(&f.hash)),
+        ),
+        (
+            QueryView::Mirrored,
+            query_frames(hashes, threshold, |f| f.mirrored.as_ref()),
+        ),
+    ];
+    views.extend((0..regions).map(|region| {
+        (
+            QueryView::Region(region),
+            query_frames(hashes, threshold, |f| {
+                f.regions.get(region).and_then(Option::as_ref)
+            }),
+        )
+    }));
+
+    let mut matches = Vec::new();
+    for (candidate_id, _) in candidates {
+        let reference: Vec<visual::Frame> = db
+            .prepare(
+                "SELECT timestamp_ms, hash_value FROM video_hashes \
+                 WHERE video_id = ? AND hash_algorithm = ? AND fingerprint_version = ? \
+                 ORDER BY frame_index",
+            )
+            .bind(&[
+                candidate_id.clone().into(),
+                hash_algorithm.into(),
+                fingerprint_version.into(),
+            ])?
+            .all()
+            .await?
+            .results::<FrameRow>()?
+            .into_iter()
+            .filter_map(|row| {
+                visual::Frame::parse(row.timestamp_ms as f64 / 1000.0, &row.hash_value)
+            })
+            .filter(|frame| visual::is_informative(&frame.hash, threshold))
+            .collect();
+
+        // Each view is scored on its own, so a match is never pieced together
+        // from flipped and unflipped frames, or from different regions.
+        let best = views
+            .iter()
+            .filter(|(_, frames)| !frames.is_empty())
+            .filter_map(|(view, frames)| {
+                let time_aligned = matches!(view, QueryView::Region(_));
+                score_view(
+                    frames,
+                    &reference,
+                    frame_interval,
+                    threshold,
+                    config,
+                    time_aligned,
+                )
+                .map(|(score, segment, similarity)| VisualMatch {
+                    video_id: candidate_id.clone(),
+                    view: *view,
+                    score,
+                    segment,
+                    similarity,
+                })
+            })
+            .max_by(|a, b| a.similarity.total_cmp(&b.similarity));
+        matches.extend(best);
+    }
+
+    Ok(matches)
+}
+
+/// Parses one view of the upload's frames, skipping frames without it and
+/// frames too plain to tell apart from a blank one.
+fn query_frames(
+    hashes: &[FrameHash],
+    threshold: u32,
+    hash: impl Fn(&FrameHash) -> Option<&String>,
+) -> Vec<visual::Frame> {
+    hashes
+        .iter()
+        .filter_map(|f| visual::Frame::parse(f.timestamp_ms as f64 / 1000.0, hash(f)?))
+        .filter(|frame| visual::is_informative(&frame.hash, threshold))
+        .collect()
+}
+
+/// Scores one view of the query against a candidate's frames. Returns
+/// `None` unless enough of the query matches or an aligned run is long enough
+/// to count as an excerpt. Frames within `threshold` bits of each other are
+/// the same frame. With `time_aligned`, only frames of the aligned
+/// run count as matched: a small region easily resembles some frame of an
+/// unrelated video, but not a run of them in order.
+fn score_view(
+    query: &[visual::Frame],
+    reference: &[visual::Frame],
+    frame_interval: f64,
+    threshold: u32,
+    config: &MatchConfig,
+    time_aligned: bool,
+) -> Option<(visual::VisualScore, Option<visual::Segment>, f64)> {
+    let aligned = visual::align(
+        query,
+        reference,
+        threshold,
+        frame_interval,
+        config
+            .segment_max_gap
+            .max(MAX_GAP_INTERVALS * frame_interval),
+    );
+    let score = if time_aligned {
+        let matched_frames = aligned.as_ref().map_or(0, |(_, frames)| *frames);
+        visual::VisualScore {
+            matched_frames,
+            similarity: matched_frames as f64 / query.len().max(1) as f64,
+        }
+    } else {
+        visual::score_candidate(query, reference, threshold)
+    };
+
+    let excerpt_density = aligned
+        .as_ref()
+        .filter(|(s, _)| s.duration() >= config.min_segment_seconds)
+        .map(|(s, frames)| *frames as f64 / ((s.duration() / frame_interval).round() + 1.0));
+    if score.similarity < config.visual_match_ratio && excerpt_density.is_none() {
+        return None;
+    }
+
+    let similarity = score
+        .similarity
+        .max(excerpt_density.unwrap_or(0.0))
+        .min(1.0);
+    Some((score, aligned.map(|(s, _)| s), similarity))
+}
+
+/// Looks up the query's audio hashes and runs offset-histogram voting over
+/// every video that shares any of them. Returns the candidates whose winning
+/// delta bin clearly beats their other alignments. `offset_seconds` is the
+/// duration of one `time_offset` step. Only hashes of the same
+/// `fingerprint_version` are compared.
+pub async fn find_audio_matches(
+    db: &D1Database,
+    video_id: &str,
+    hashes: &[AudioHash],
+    fingerprint_version: u32,
+    offset_seconds: f64,
+    config: &MatchConfig,
+) -> Result<Vec<AudioMatch>> {
+    let mut query: HashMap<i64, Vec<i64>> = HashMap::new();
+    for hash in hashes {
+        query
+            .entry(hash.hash as i64)
+            .or_default()
+            .push(hash.time_offset as i64);
+    }
+
+    // Sample the distinct hashes evenly across the clip so long uploads stay
+    // within a bounded number of lookups.
+    let mut keys: Vec<i64> = query.keys().copied().collect();
+    keys.sort_by_key(|k| query[k][0]);
+    let step = keys.len().div_ceil(config.audio_query_hashes.max(1)).max(1);
+    let keys: Vec<i64> = keys.into_iter().step_by(step).collect();
+
+    let mut statements = Vec::new();
+    for chunk in keys.chunks(MAX_BOUND_PARAMS) {
+        let placeholders = vec!["?"; chunk.len()].join(", ");
+        let params: Vec<JsValue> = std::iter::once(JsValue::from(fingerprint_version))
+            .chain(chunk.iter().map(|&k| JsValue::from(k as f64)))
+            .collect();
+        statements.push(
+            db.prepare(format!(
+                "SELECT video_id, hash, time_offset FROM audio_hashes \
+                 WHERE fingerprint_version = ? AND hash IN ({})",
+                placeholders
+            ))
+            .bind(&params)?,
+        );
+    }
+
+    let mut hits = Vec::new();
+    for chunk in statements.chunks(100) {
+        for result in db.batch(chunk.to_vec()).await? {
+            for row in result.results::<AudioHitRow>()? {
+                if row.video_id != video_id {
+                    hits.push(audio::AudioHit {
+                        video_id: row.video_id,
+                        hash: row.hash,
+                        time_offset: row.time_offset,
+                    });
+                }
+            }
+        }
+    }
+
+    let sampled: HashMap<i64, Vec<i64>> = keys.iter().map(|k| (*k, query[k].clone())).collect();
+
+    // Pitch/tempo-invariant hashes also match copies played at another
+    // speed, whose offsets drift apart as they play, so they vote coarser.
+    let bin_width = match hashes.first().map(|h| AudioHashKey::decode(h.hash)) {
+        Some(Ok(AudioHashKey::Ratio { .. })) => config.audio_invariant_bin,
+        _ => 1,
+    };
+
+    Ok(audio::vote(&sampled, &hits, bin_width)
+        .into_iter()
+        .filter(|score| score.is_match(config.audio_min_votes, config.audio_peak_ratio))
+        .map(|score| {
+            let in_segment: usize = sampled
+                .values()
+                .flatten()
+                .filter(|&&offset| offset >= score.query_start && offset <= score.query_end)
+                .count();
+            let query_start = score.query_start as f64 * offset_seconds;
+            let query_end = (score.query_end + 1) as f64 * offset_seconds;
+            let shift = score.offset_delta as f64 * offset_seconds;
+            AudioMatch {
+                segment: visual::Segment {
+                    query_start,
+                    query_end,
+                    reference_start: query_start + shift,
+                    reference_end: query_end + shift,
+                },
+                similarity: (score.votes as f64 / in_segment.max(1) as f64).min(1.0),
+                score,
+            }
+        })
+        // A few votes over a blip of audio score as well as a long copy.
+        .filter(|m| m.segment.duration() >= config.min_segment_seconds)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,224 @@
+use crate::config::MatchConfig;
+use crate::matcher::audio::{vote, AudioHit};
+use crate::matcher::visual::{
+    align, decode_hex, hamming_distance, is_informative, lsh_bands, score_candidate, Frame,
+};
+use crate::matcher::{query_frames, score_view};
+use crate::FrameHash;
+use std::collections::HashMap;
+
+#[test]
+fn test_hamming_distance() {
+    let a = decode_hex("ff00ff00ff00ff00").unwrap();
+    let b = decode_hex("ff00ff00ff00ff01").unwrap();
+    assert_eq!(hamming_distance(&a, &a), Some(0));
+    assert_eq!(hamming_distance(&a, &b), Some(1));
+    assert_eq!(hamming_distance(&a, &b[..4]), None);
+}
+
+#[test]
+fn test_decode_rejects_non_hex() {
+    assert_eq!(decode_hex("00ff"), Some(vec![0x00, 0xff]));
+    assert_eq!(decode_hex("0g"), None);
+    assert_eq!(decode_hex("+f"), None);
+    // Multi-byte characters must not be split mid-character.
+    assert_eq!(decode_hex("éé"), None);
+    assert_eq!(decode_hex("aé"), None);
+    assert!(lsh_bands("ééé0").is_empty());
+    assert!(lsh_bands("0001000200030g04").is_empty());
+}
+
+#[test]
+fn test_lsh_bands() {
+    let bands = lsh_bands("0001000200030004");
+    assert_eq!(bands, vec![(0, 1), (1, 2), (2, 3), (3, 4)]);
+    assert!(lsh_bands("abc").is_empty());
+}
+
+fn frames(hashes: &[&str]) -> Vec<Frame> {
+    hashes
+        .iter()
+        .enumerate()
+        .map(|(i, h)| Frame::parse(i as f64, h).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_score_tolerates_flipped_bits() {
+    let reference = frames(&["ff00ff00ff00ff00", "0f0f0f0f0f0f0f0f"]);
+    // Each query frame is a few bits away from a reference frame, as happens
+    // after re-encoding or a brightness tweak.
+    let query = frames(&["ff00ff00ff00ff03", "0f0f0f0f0f0f0f1f"]);
+
+    let score = score_candidate(&query, &reference, 4);
+    assert_eq!(score.matched_frames, 2);
+    assert_eq!(score.similarity, 1.0);
+
+    let strict = score_candidate(&query, &reference, 0);
+    assert_eq!(strict.matched_frames, 0);
+}
+
+#[test]
+fn test_align_finds_excerpt() {
+    // A 40 frame reference of distinct hashes; the upload is 5 unrelated
+    // frames followed by reference frames 20..32.
+    let reference: Vec<String> = (0..40).map(distinct).collect();
+    let mut query: Vec<String> = (100..105).map(distinct).collect();
+    query.extend(reference[20..32].iter().cloned());
+
+    let as_frames = |hashes: &[String]| {
+        let refs: Vec<&str> = hashes.iter().map(String::as_str).collect();
+        frames(&refs)
+    };
+    let (segment, aligned) =
+        align(&as_frames(&query), &as_frames(&reference), 4, 1.0, 3.0).unwrap();
+
+    assert_eq!(segment.query_start, 5.0);
+    assert_eq!(segment.query_end, 16.0);
+    assert_eq!(segment.reference_start, 20.0);
+    assert_eq!(segment.reference_end, 31.0);
+    assert_eq!(segment.duration(), 11.0);
+    assert_eq!(aligned, 12);
+}
+
+fn config() -> MatchConfig {
+    MatchConfig {
+        hamming_ratio: 0.16,
+        visual_match_ratio: 0.5,
+        max_candidates: 10,
+        min_segment_seconds: 10.0,
+        segment_max_gap: 3.0,
+        audio_query_hashes: 2000,
+        audio_min_votes: 10,
+        audio_peak_ratio: 3.0,
+        audio_invariant_bin: 8,
+    }
+}
+
+#[test]
+fn test_hamming_threshold_scales_with_hash_length() {
+    let config = config();
+    assert_eq!(config.hamming_threshold(16), 3);
+    assert_eq!(config.hamming_threshold(64), 10);
+    assert_eq!(config.hamming_threshold(256), 41);
+}
+
+fn distinct(i: u64) -> String {
+    format!("{:016x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+}
+
+#[test]
+fn test_uniform_region_does_not_match_black_frame() {
+    // The upload's first cell is a plain dark corner; dHash gives it the
+    // same all-zero hash as the fade to black in the reference.
+    let black = "0000000000000000";
+    let mut reference: Vec<String> = (0..30).map(distinct).collect();
+    reference[15] = black.to_string();
+    let reference = frames(&reference.iter().map(String::as_str).collect::<Vec<_>>());
+    let upload: Vec<FrameHash> = (0..20)
+        .map(|i| FrameHash {
+            hash: distinct(100 + i),
+            timestamp_ms: i * 1000,
+            mirrored: None,
+            regions: vec![
+                Some(black.to_string()),
+                Some("0000000000000001".to_string()),
+            ],
+        })
+        .collect();
+
+    let config = config();
+    let threshold = config.hamming_threshold(64);
+    assert!(!is_informative(&decode_hex(black).unwrap(), threshold));
+    for region in 0..2 {
+        let query = query_frames(&upload, threshold, |f| {
+            f.regions.get(region).and_then(Option::as_ref)
+        });
+        assert!(query.is_empty());
+    }
+
+    // Even when it gets through, one reference frame matching every query
+    // frame is no aligned run.
+    let query = frames(&[black; 20]);
+    assert!(score_view(&query, &reference, 1.0, 10, &config, false).is_some());
+    assert!(score_view(&query, &reference, 1.0, 10, &config, true).is_none());
+}
+
+#[test]
+fn test_region_matches_need_time_alignment() {
+    let reference: Vec<String> = (0..30).map(distinct).collect();
+    let as_frames =
+        |hashes: &[String]| frames(&hashes.iter().map(String::as_str).collect::<Vec<_>>());
+    let config = config();
+
+    // Reference frames 5..25 in order: a copy in one region of the upload.
+    let copy = as_frames(&reference[5..25]);
+    let reference = as_frames(&reference);
+    let (_, _, similarity) = score_view(&copy, &reference, 1.0, 10, &config, true).unwrap();
+    assert_eq!(similarity, 1.0);
+
+    // The same frames in reverse order each match somewhere, but no two at
+    // the same offset.
+    let mut reversed = copy.clone();
+    reversed.reverse();
+    for (i, frame) in reversed.iter_mut().enumerate() {
+        frame.time = i as f64;
+    }
+    assert!(score_view(&reversed, &reference, 1.0, 10, &config, false).is_some());
+    assert!(score_view(&reversed, &reference, 1.0, 10, &config, true).is_none());
+}
+
+fn hit(video_id: &str, hash: i64, time_offset: i64) -> AudioHit {
+    AudioHit {
+        video_id: video_id.to_string(),
+        hash,
+        time_offset,
+    }
+}
+
+#[test]
+fn test_vote_finds_shifted_alignment() {
+    // The query is the reference shifted by 40 windows.
+    let query: HashMap<i64, Vec<i64>> = (0..30).map(|h| (h, vec![h * 2])).collect();
+    let mut hits: Vec<AudioHit> = (0..30).map(|h| hit("orig", h, h * 2 + 40)).collect();
+    // A few random collisions with another video at scattered deltas.
+    hits.extend((0..5).map(|h| hit("noise", h, h * 17)));
+
+    let scores = vote(&query, &hits, 1);
+    let best = &scores[0];
+    assert_eq!(best.video_id, "orig");
+    assert_eq!(best.offset_delta, 40);
+    assert_eq!(best.votes, 30);
+    assert_eq!((best.query_start, best.query_end), (0, 58));
+    assert!(best.is_match(10, 3.0));
+
+    let noise = scores.iter().find(|s| s.video_id == "noise").unwrap();
+    assert!(!noise.is_match(10, 3.0));
+}
+
+#[test]
+fn test_vote_rejects_scattered_collisions() {
+    // Many hits, but no delta bin stands out from the rest.
+    let query: HashMap<i64, Vec<i64>> = (0..40).map(|h| (h, vec![0])).collect();
+    let hits: Vec<AudioHit> = (0..40).map(|h| hit("other", h, (h % 10) * 10)).collect();
+
+    let scores = vote(&query, &hits, 1);
+    assert_eq!(scores[0].votes, 4);
+    assert!(!scores[0].is_match(3, 3.0));
+}
+
+#[test]
+fn test_coarse_bins_absorb_tempo_drift() {
+    // The reference plays 2% slower, so the delta grows by one every 50 offsets.
+    let query: HashMap<i64, Vec<i64>> = (0..100).map(|h| (h, vec![h * 5])).collect();
+    let hits: Vec<AudioHit> = (0..100)
+        .map(|h| hit("orig", h, h * 5 * 102 / 100 + 40))
+        .collect();
+
+    let exact = &vote(&query, &hits, 1)[0];
+    assert!(!exact.is_match(30, 3.0));
+
+    let coarse = &vote(&query, &hits, 8)[0];
+    assert_eq!(coarse.offset_delta, 40);
+    assert!(coarse.is_match(30, 3.0));
+}
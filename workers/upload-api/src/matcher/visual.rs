@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+/// Hex characters per LSH band. A frame hash of `n` bits is split into
+/// `n / 16` bands, so two hashes that differ in fewer than `n / 16` bits
+/// always share at least one band: 3 bits for a 64-bit hash, none for a
+/// 16-bit one. Hashes further apart are only found when their differences
+/// leave some band untouched.
+const BAND_HEX_CHARS: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VisualScore {
+    pub matched_frames: usize,
+    pub similarity: f64,
+}
+
+/// Decodes a hex hash, or returns `None` if it is not made of pairs of hex
+/// digits. Works on bytes, so any other character is rejected rather than
+/// split.
+pub fn decode_hex(hash: &str) -> Option<Vec<u8>> {
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    let digits = hash.as_bytes();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| Some(digit(pair[0])? << 4 | digit(pair[1])?))
+        .collect()
+}
+
+/// Hamming distance between two decoded hashes. Returns `None` when the
+/// hashes have different lengths, since they were produced by different
+/// hashers and cannot be compared.
+pub fn hamming_distance(a: &[u8], b: &[u8]) -> Option<u32> {
+    if a.len() != b.len() {
+        return None;
+    }
+    Some(a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum())
+}
+
+/// Whether a hash has more than `threshold` bits set and more than
+/// `threshold` bits clear. Uniform pictures hash to all zeros or all ones, so
+/// anything closer than that matches every blank or black frame.
+pub fn is_informative(hash: &[u8], threshold: u32) -> bool {
+    let ones: u32 = hash.iter().map(|b| b.count_ones()).sum();
+    let zeros = hash.len() as u32 * 8 - ones;
+    ones > threshold && zeros > threshold
+}
+
+/// Splits a hex hash into `(band_index, band_value)` pairs as stored in
+/// `video_lsh_bands`. Empty when the hash is not valid hex or does not split
+/// evenly into bands.
+pub fn lsh_bands(hash: &str) -> Vec<(i32, i32)> {
+    if !hash.len().is_multiple_of(BAND_HEX_CHARS) {
+        return Vec::new();
+    }
+    let Some(bytes) = decode_hex(hash) else {
+        return Vec::new();
+    };
+    bytes
+        .chunks(BAND_HEX_CHARS / 2)
+        .enumerate()
+        .map(|(b, band)| (b as i32, u16::from_be_bytes([band[0], band[1]]) as i32))
+        .collect()
+}
+
+/// Scores a candidate by counting how many query frames have a reference frame
+/// within `threshold` bits, regardless of where in the reference it sits.
+pub fn score_candidate(query: &[Frame], reference: &[Frame], threshold: u32) -> VisualScore {
+    let matched_frames = query
+        .iter()
+        .filter(|q| {
+            reference
+                .iter()
+                .filter_map(|r| hamming_distance(&q.hash, &r.hash))
+                .min()
+                .is_some_and(|d| d <= threshold)
+        })
+        .count();
+
+    let similarity = if query.is_empty() {
+        0.0
+    } else {
+        matched_frames as f64 / query.len() as f64
+    };
+
+    VisualScore {
+        matched_frames,
+        similarity,
+    }
+}
+
+/// A decoded frame hash and its position in seconds from the start of the video.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub time: f64,
+    pub hash: Vec<u8>,
+}
+
+impl Frame {
+    pub fn parse(time: f64, hash: &str) -> Option<Self> {
+        decode_hex(hash).map(|hash| Self { time, hash })
+    }
+}
+
+/// Matched time ranges, in seconds, in the query and the reference video.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Segment {
+    pub query_start: f64,
+    pub query_end: f64,
+    pub reference_start: f64,
+    pub reference_end: f64,
+}
+
+impl Segment {
+    pub fn duration(&self) -> f64 {
+        self.query_end - self.query_start
+    }
+}
+
+/// Finds the longest run of query frames that line up with the reference at a
+/// single time offset. Each matching frame pair votes for `reference - query`
+/// rounded to `frame_interval`; the winning offset's pairs are then split into
+/// runs wherever consecutive query frames are more than `max_gap` seconds apart.
+/// Returns the run's time ranges, from its first to its last aligned frame,
+/// and how many query frames it aligned.
+pub fn align(
+    query: &[Frame],
+    reference: &[Frame],
+    threshold: u32,
+    frame_interval: f64,
+    max_gap: f64,
+) -> Option<(Segment, usize)> {
+    let mut pairs: Vec<(usize, usize, i64)> = Vec::new();
+    for (qi, q) in query.iter().enumerate() {
+        for (ri, r) in reference.iter().enumerate() {
+            if hamming_distance(&q.hash, &r.hash).is_some_and(|d| d <= threshold) {
+                let bin = ((r.time - q.time) / frame_interval).round() as i64;
+                pairs.push((qi, ri, bin));
+            }
+        }
+    }
+
+    let mut histogram: HashMap<i64, usize> = HashMap::new();
+    for (_, _, bin) in &pairs {
+        *histogram.entry(*bin).or_default() += 1;
+    }
+    let window = |bin: i64| -> usize {
+        (bin.saturating_sub(1)..=bin.saturating_add(1))
+            .map(|b| histogram.get(&b).copied().unwrap_or(0))
+            .sum()
+    };
+    let best = histogram
+        .keys()
+        .map(|&bin| (bin, window(bin)))
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))?
+        .0;
+
+    // Keep one reference frame per query frame: the one closest to the
+    // winning offset.
+    let mut aligned: Vec<(usize, usize)> = Vec::new();
+    for (qi, ri, bin) in pairs {
+        if bin.abs_diff(best) > 1 {
+            continue;
+        }
+        match aligned.last_mut() {
+            Some(last) if last.0 == qi => {
+                let current = (reference[last.1].time - query[qi].time) / frame_interval;
+                let candidate = (reference[ri].time - query[qi].time) / frame_interval;
+                if (candidate - best as f64).abs() < (current - best as f64).abs() {
+                    last.1 = ri;
+                }
+            }
+            _ => aligned.push((qi, ri)),
+        }
+    }
+
+    let mut runs: Vec<&[(usize, usize)]> = Vec::new();
+    let mut start = 0;
+    for i in 1..=aligned.len() {
+        if i == aligned.len() || query[aligned[i].0].time - query[aligned[i - 1].0].time > max_gap {
+            runs.push(&aligned[start..i]);
+            start = i;
+        }
+    }
+
+    let run = runs.into_iter().max_by(|a, b| {
+        let span = |r: &[(usize, usize)]| query[r[r.len() - 1].0].time - query[r[0].0].time;
+        span(a).total_cmp(&span(b))
+    })?;
+
+    let (query_first, query_last) = (run[0].0, run[run.len() - 1].0);
+    let reference_times = run.iter().map(|(_, ri)| reference[*ri].time);
+    let reference_start = reference_times.clone().fold(f64::INFINITY, f64::min);
+    let reference_end = reference_times.fold(f64::NEG_INFINITY, f64::max);
+
+    let segment = Segment {
+        query_start: query[query_first].time,
+        query_end: query[query_last].time,
+        reference_start,
+        reference_end,
+    };
+    Some((segment, run.len()))
+}
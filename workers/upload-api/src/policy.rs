@@ -0,0 +1,278 @@
+use crate::config::env_or;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use worker::*;
+
+/// Lifecycle of a row in `videos`. Uploads start in `Processing`; the matcher
+/// moves them to one of the other states and moderators move them between
+/// `NeedsReview`, `Active` and `Blocked`. Flagged videos go back to
+/// `Processing` when they have to be re-evaluated, e.g. after their original
+/// was deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoStatus {
+    Processing,
+    Active,
+    NeedsReview,
+    Blocked,
+    /// Dispatch gave up on the processing job.
+    Failed,
+}
+
+impl VideoStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VideoStatus::Processing => "processing",
+            VideoStatus::Active => "active",
+            VideoStatus::NeedsReview => "needs_review",
+            VideoStatus::Blocked => "blocked",
+            VideoStatus::Failed => "failed",
+        }
+    }
+
+    pub fn can_transition_to(&self, next: VideoStatus) -> bool {
+        use VideoStatus::*;
+        matches!(
+            (self, next),
+            (Processing, Active | NeedsReview | Blocked | Failed)
+                | (NeedsReview, Active | Blocked | Processing)
+                | (Active, Blocked)
+                | (Blocked, Active | Processing)
+                | (Failed, Processing)
+        )
+    }
+
+    /// Whether the video's fingerprints belong in the match index.
+    pub fn is_indexed(&self) -> bool {
+        matches!(self, VideoStatus::Active | VideoStatus::NeedsReview)
+    }
+}
+
+impl fmt::Display for VideoStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for VideoStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "processing" => Ok(VideoStatus::Processing),
+            "active" => Ok(VideoStatus::Active),
+            "needs_review" => Ok(VideoStatus::NeedsReview),
+            "blocked" => Ok(VideoStatus::Blocked),
+            "failed" => Ok(VideoStatus::Failed),
+            other => Err(format!("Unknown video status: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PolicyConfig {
+    /// Confidence at or above which an upload is blocked outright.
+    pub block_threshold: f64,
+    /// Confidence at or above which an upload is held for a moderator.
+    pub review_threshold: f64,
+}
+
+impl PolicyConfig {
+    pub fn from_env(env: &Env) -> Self {
+        Self {
+            block_threshold: env_or(env, "BLOCK_THRESHOLD", 0.85),
+            review_threshold: env_or(env, "REVIEW_THRESHOLD", 0.5),
+        }
+    }
+
+    /// Maps a combined confidence to a status, along with the threshold that
+    /// decided it.
+    pub fn decide(&self, confidence: f64) -> (VideoStatus, f64) {
+        if confidence >= self.block_threshold {
+            (VideoStatus::Blocked, self.block_threshold)
+        } else if confidence >= self.review_threshold {
+            (VideoStatus::NeedsReview, self.review_threshold)
+        } else {
+            (VideoStatus::Active, self.review_threshold)
+        }
+    }
+}
+
+/// Status a moderator's `decision` moves a video in `current` to. A blocked
+/// video was never indexed, so letting it through sends it back to
+/// `Processing` to be fingerprinted first.
+pub fn review_status(current: VideoStatus, decision: VideoStatus) -> VideoStatus {
+    match (current, decision) {
+        (VideoStatus::Blocked, VideoStatus::Active) => VideoStatus::Processing,
+        _ => decision,
+    }
+}
+
+/// Status a processed video is given: the matcher's `decision`, or `Active`
+/// when nothing matched. A video a moderator `approved` is made active
+/// whatever the matcher finds, so their decision is not undone.
+pub fn processed_status(approved: bool, decision: Option<VideoStatus>) -> VideoStatus {
+    match decision {
+        Some(decision) if !approved => decision,
+        _ => VideoStatus::Active,
+    }
+}
+
+#[derive(Deserialize)]
+struct StatusRow {
+    status: String,
+}
+
+/// Why a video could not be moved to another status.
+#[derive(Debug)]
+pub enum TransitionError {
+    NotFound(String),
+    Illegal {
+        video_id: String,
+        from: VideoStatus,
+        to: VideoStatus,
+    },
+    /// Another writer changed the status between the read and the update.
+    Conflict(String),
+    Database(Error),
+}
+
+impl TransitionError {
+    /// HTTP status the error is reported with.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            TransitionError::NotFound(_) => 404,
+            TransitionError::Illegal { .. } => 422,
+            TransitionError::Conflict(_) => 409,
+            TransitionError::Database(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransitionError::NotFound(id) => write!(f, "Video {} not found", id),
+            TransitionError::Illegal { video_id, from, to } => write!(
+                f,
+                "Illegal status transition {} -> {} for video {}",
+                from, to, video_id
+            ),
+            TransitionError::Conflict(id) => {
+                write!(f, "Status of video {} changed concurrently", id)
+            }
+            TransitionError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<Error> for TransitionError {
+    fn from(e: Error) -> Self {
+        TransitionError::Database(e)
+    }
+}
+
+impl From<TransitionError> for Error {
+    fn from(e: TransitionError) -> Self {
+        match e {
+            TransitionError::Database(e) => e,
+            other => Error::RustError(other.to_string()),
+        }
+    }
+}
+
+/// Moves a video to `next`, refusing transitions the state machine does not
+/// allow. The update is guarded on the status that was read, so a concurrent
+/// writer that got there first makes it fail with `Conflict` rather than skip
+/// a state. `original_video_id` is only written when set. Entering
+/// `Processing` restarts the clock the stuck-job sweep goes by.
+pub async fn transition(
+    db: &D1Database,
+    video_id: &str,
+    next: VideoStatus,
+    original_video_id: Option<&str>,
+) -> std::result::Result<(), TransitionError> {
+    let row = db
+        .prepare("SELECT status FROM videos WHERE id = ?")
+        .bind(&[video_id.into()])?
+        .first::<StatusRow>(None)
+        .await?
+        .ok_or_else(|| TransitionError::NotFound(video_id.to_string()))?;
+    let current: VideoStatus = row.status.parse().map_err(Error::RustError)?;
+
+    if !current.can_transition_to(next) {
+        return Err(TransitionError::Illegal {
+            video_id: video_id.to_string(),
+            from: current,
+            to: next,
+        });
+    }
+
+    let restart = if next == VideoStatus::Processing {
+        ", processing_started_at = strftime('%s', 'now'), processing_attempts = 0"
+    } else {
+        ""
+    };
+    let result = db
+        .prepare(format!("UPDATE videos SET status = ?, original_video_id = COALESCE(?, original_video_id){} WHERE id = ? AND status = ?", restart))
+        .bind(&[
+            next.as_str().into(),
+            original_video_id.into(),
+            video_id.into(),
+            current.as_str().into(),
+        ])?
+        .run()
+        .await?;
+    let changes = result.meta()?.and_then(|meta| meta.changes).unwrap_or(0);
+    if changes != 1 {
+        return Err(TransitionError::Conflict(video_id.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitions() {
+        use VideoStatus::*;
+        assert!(Processing.can_transition_to(NeedsReview));
+        assert!(NeedsReview.can_transition_to(Blocked));
+        assert!(Blocked.can_transition_to(Active));
+        assert!(!Active.can_transition_to(Processing));
+        assert!(Blocked.can_transition_to(Processing));
+        assert!(Processing.can_transition_to(Failed));
+        assert!(!Failed.can_transition_to(Active));
+        assert!(!Blocked.can_transition_to(NeedsReview));
+    }
+
+    #[test]
+    fn test_decide() {
+        let policy = PolicyConfig {
+            block_threshold: 0.85,
+            review_threshold: 0.5,
+        };
+        assert_eq!(policy.decide(0.9), (VideoStatus::Blocked, 0.85));
+        assert_eq!(policy.decide(0.6), (VideoStatus::NeedsReview, 0.5));
+        assert_eq!(policy.decide(0.2), (VideoStatus::Active, 0.5));
+    }
+
+    #[test]
+    fn test_unblocked_video_ends_up_active() {
+        use VideoStatus::*;
+        let next = review_status(Blocked, Active);
+        assert_eq!(next, Processing);
+        assert!(Blocked.can_transition_to(next));
+
+        // The video still matches the original it was blocked for.
+        let status = processed_status(true, Some(Blocked));
+        assert_eq!(status, Active);
+        assert!(next.can_transition_to(status));
+
+        assert_eq!(processed_status(false, Some(Blocked)), Blocked);
+        assert_eq!(processed_status(false, None), Active);
+        assert_eq!(review_status(NeedsReview, Active), Active);
+    }
+}
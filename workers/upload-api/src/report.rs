@@ -0,0 +1,79 @@
+use crate::matcher::visual::Segment;
+use crate::policy::{PolicyConfig, VideoStatus};
+use serde::Serialize;
+use worker::wasm_bindgen::JsValue;
+use worker::*;
+
+/// Outcome of scoring an upload against its best candidate. Returned by
+/// `/internal/complete` and stored in `match_reports` for moderators.
+#[derive(Debug, Clone, Serialize)]
+pub struct MatchReport {
+    pub id: String,
+    pub video_id: String,
+    pub candidate_video_id: String,
+    pub visual_similarity: f64,
+    pub audio_similarity: f64,
+    pub confidence: f64,
+    pub matched_frames: usize,
+    /// Status the policy assigned to the upload.
+    pub decision: VideoStatus,
+    /// Policy threshold that produced `decision`.
+    pub threshold: f64,
+    pub matched_range: Option<Segment>,
+}
+
+impl MatchReport {
+    pub fn new(
+        video_id: &str,
+        candidate_video_id: &str,
+        visual_similarity: f64,
+        audio_similarity: f64,
+        matched_frames: usize,
+        policy: &PolicyConfig,
+        matched_range: Option<Segment>,
+    ) -> Self {
+        let confidence = combine_confidence(visual_similarity, audio_similarity);
+        let (decision, threshold) = policy.decide(confidence);
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            video_id: video_id.to_string(),
+            candidate_video_id: candidate_video_id.to_string(),
+            visual_similarity,
+            audio_similarity,
+            confidence,
+            matched_frames,
+            decision,
+            threshold,
+            matched_range,
+        }
+    }
+
+    pub fn insert(&self, db: &D1Database) -> Result<D1PreparedStatement> {
+        let range = self.matched_range.as_ref();
+        let time = |f: fn(&Segment) -> f64| -> JsValue { range.map(f).into() };
+        db.prepare(
+            "INSERT INTO match_reports (id, video_id, candidate_video_id, visual_similarity, audio_similarity, confidence, matched_frames, decision, threshold, query_start, query_end, reference_start, reference_end) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&[
+            self.id.clone().into(),
+            self.video_id.clone().into(),
+            self.candidate_video_id.clone().into(),
+            self.visual_similarity.into(),
+            self.audio_similarity.into(),
+            self.confidence.into(),
+            (self.matched_frames as i32).into(),
+            self.decision.as_str().into(),
+            self.threshold.into(),
+            time(|s| s.query_start),
+            time(|s| s.query_end),
+            time(|s| s.reference_start),
+            time(|s| s.reference_end),
+        ])
+    }
+}
+
+/// Treats the two similarities as independent evidence: the combined
+/// confidence is the probability that at least one of them is right.
+pub fn combine_confidence(visual: f64, audio: f64) -> f64 {
+    1.0 - (1.0 - visual.clamp(0.0, 1.0)) * (1.0 - audio.clamp(0.0, 1.0))
+}
@@ -0,0 +1,354 @@
+use crate::config::FingerprintVersions;
+use crate::dispatch::{JobQueue, ProcessingJob};
+use crate::policy::{self, VideoStatus};
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+/// A row of the `videos` table as returned to clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Video {
+    pub id: String,
+    pub r2_key: String,
+    pub user_id: String,
+    pub status: String,
+    pub original_video_id: Option<String>,
+    /// Unix seconds when the row was created.
+    pub created_at: i64,
+    pub uploaded_at: Option<String>,
+    /// When the matcher finished with the upload.
+    pub processed_at: Option<String>,
+    /// When a moderator unblocked the video, overriding the matcher.
+    pub approved_at: Option<String>,
+}
+
+const COLUMNS: &str =
+    "id, r2_key, user_id, status, original_video_id, created_at, uploaded_at, processed_at, approved_at";
+
+/// A video waiting on the processor, with what it takes to send its job
+/// again.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Pending {
+    pub id: String,
+    pub r2_key: String,
+    /// Times the job was sent again since the video entered `processing`.
+    pub processing_attempts: u32,
+}
+
+#[derive(Deserialize)]
+struct DependentRow {
+    id: String,
+    r2_key: String,
+    status: String,
+}
+
+pub async fn get(db: &D1Database, id: &str) -> Result<Option<Video>> {
+    db.prepare(format!("SELECT {} FROM videos WHERE id = ?", COLUMNS))
+        .bind(&[id.into()])?
+        .first::<Video>(None)
+        .await
+}
+
+/// The video, if it is still `processing`.
+pub async fn pending(db: &D1Database, id: &str) -> Result<Option<Pending>> {
+    db.prepare("SELECT id, r2_key, processing_attempts FROM videos WHERE id = ? AND status = ?")
+        .bind(&[id.into(), VideoStatus::Processing.as_str().into()])?
+        .first::<Pending>(None)
+        .await
+}
+
+/// Up to `limit` videos that entered `processing` before `started_before`
+/// (Unix seconds) and are still there, oldest first.
+pub async fn stuck(db: &D1Database, started_before: i64, limit: usize) -> Result<Vec<Pending>> {
+    db.prepare(
+        "SELECT id, r2_key, processing_attempts FROM videos \
+         WHERE status = ? AND processing_started_at < ? \
+         ORDER BY processing_started_at LIMIT ?",
+    )
+    .bind(&[
+        VideoStatus::Processing.as_str().into(),
+        (started_before as f64).into(),
+        (limit as i32).into(),
+    ])?
+    .all()
+    .await?
+    .results::<Pending>()
+}
+
+/// Counts another attempt at processing the video and restarts its clock.
+/// Returns `false` when the video left `processing`, or another retry got
+/// there first.
+pub async fn restart_processing(db: &D1Database, video: &Pending) -> Result<bool> {
+    let result = db
+        .prepare(
+            "UPDATE videos SET processing_attempts = ?, processing_started_at = strftime('%s', 'now') \
+             WHERE id = ? AND status = ? AND processing_attempts = ?",
+        )
+        .bind(&[
+            (video.processing_attempts + 1).into(),
+            video.id.as_str().into(),
+            VideoStatus::Processing.as_str().into(),
+            video.processing_attempts.into(),
+        ])?
+        .run()
+        .await?;
+    Ok(result.meta()?.and_then(|meta| meta.changes).unwrap_or(0) == 1)
+}
+
+/// Statements that drop a video's fingerprints from the match index.
+pub fn delete_fingerprints(db: &D1Database, id: &str) -> Result<Vec<D1PreparedStatement>> {
+    ["video_hashes", "video_lsh_bands", "audio_hashes"]
+        .iter()
+        .map(|table| {
+            db.prepare(format!("DELETE FROM {} WHERE video_id = ?", table))
+                .bind(&[id.into()])
+        })
+        .collect()
+}
+
+/// Removes the video's R2 object and every row that belongs to it. Videos that
+/// were flagged as copies of it lose their fingerprints and go back to
+/// `processing`, and their jobs go on `queue`; the ids of those videos are
+/// returned. A job lost to a failed send is re-queued by the stuck-video
+/// sweep, since entering `processing` starts its clock.
+pub async fn delete(
+    db: &D1Database,
+    bucket: &Bucket,
+    queue: &impl JobQueue,
+    video: &Video,
+) -> Result<Vec<String>> {
+    // R2 first: if it fails nothing has changed and the delete can be retried.
+    bucket.delete(&video.r2_key).await?;
+
+    let mut statements = delete_fingerprints(db, &video.id)?;
+    // Reports naming the video as the original go too, or they would point
+    // at a row that no longer exists.
+    statements.push(
+        db.prepare("DELETE FROM match_reports WHERE video_id = ? OR candidate_video_id = ?")
+            .bind(&[video.id.as_str().into(), video.id.as_str().into()])?,
+    );
+    statements.push(
+        db.prepare("DELETE FROM videos WHERE id = ?")
+            .bind(&[video.id.as_str().into()])?,
+    );
+    db.batch(statements).await?;
+
+    let dependents = db
+        .prepare("SELECT id, r2_key, status FROM videos WHERE original_video_id = ?")
+        .bind(&[video.id.as_str().into()])?
+        .all()
+        .await?
+        .results::<DependentRow>()?;
+
+    let mut reevaluated = Vec::new();
+    for dependent in dependents {
+        let mut statements = vec![db
+            .prepare("UPDATE videos SET original_video_id = NULL WHERE id = ?")
+            .bind(&[dependent.id.as_str().into()])?];
+
+        let job = rematch(&dependent)?;
+        if job.is_some() {
+            statements.extend(delete_fingerprints(db, &dependent.id)?);
+        }
+        db.batch(statements).await?;
+
+        if let Some(job) = job {
+            policy::transition(db, &dependent.id, VideoStatus::Processing, None).await?;
+            queue.send(&job).await?;
+            reevaluated.push(job.video_id);
+        }
+    }
+
+    Ok(reevaluated)
+}
+
+/// The job that matches a copy of a deleted video again, for copies the matcher
+/// flagged. Active copies were let through and stay as they are; copies still
+/// processing will be matched without the deleted video anyway.
+fn rematch(dependent: &DependentRow) -> Result<Option<ProcessingJob>> {
+    let status: VideoStatus = dependent.status.parse().map_err(Error::RustError)?;
+    Ok(status
+        .can_transition_to(VideoStatus::Processing)
+        .then(|| ProcessingJob {
+            video_id: dependent.id.clone(),
+            r2_key: dependent.r2_key.clone(),
+            refingerprint: false,
+            attempts: 0,
+        }))
+}
+
+/// Keyset position in the `created_at DESC, id DESC` ordering. `created_at`
+/// has one-second resolution, so the id breaks ties.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    pub created_at: i64,
+    pub id: String,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        format!("{}_{}", self.created_at, self.id)
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        let (created_at, id) = s.split_once('_')?;
+        Some(Self {
+            created_at: created_at.parse().ok()?,
+            id: id.to_string(),
+        })
+    }
+
+    /// Condition selecting the rows after the cursor, and its parameters.
+    fn after(&self) -> (&'static str, [wasm_bindgen::JsValue; 3]) {
+        (
+            "(created_at < ? OR (created_at = ? AND id < ?))",
+            [
+                (self.created_at as f64).into(),
+                (self.created_at as f64).into(),
+                self.id.as_str().into(),
+            ],
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ListQuery {
+    pub user_id: Option<String>,
+    pub status: Option<String>,
+    pub limit: usize,
+    pub cursor: Option<Cursor>,
+}
+
+/// Returns one page of videos, newest first, and the cursor for the next page
+/// when there is one.
+pub async fn list(db: &D1Database, query: &ListQuery) -> Result<(Vec<Video>, Option<String>)> {
+    let mut clauses = Vec::new();
+    let mut params: Vec<wasm_bindgen::JsValue> = Vec::new();
+
+    if let Some(user_id) = &query.user_id {
+        clauses.push("user_id = ?");
+        params.push(user_id.as_str().into());
+    }
+    if let Some(status) = &query.status {
+        clauses.push("status = ?");
+        params.push(status.as_str().into());
+    }
+    if let Some(cursor) = &query.cursor {
+        let (after, values) = cursor.after();
+        clauses.push(after);
+        params.extend(values);
+    }
+
+    let filter = if clauses.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", clauses.join(" AND "))
+    };
+    // Fetch one extra row to learn whether another page follows.
+    params.push(((query.limit + 1) as i32).into());
+
+    let videos = db
+        .prepare(format!(
+            "SELECT {} FROM videos {} ORDER BY created_at DESC, id DESC LIMIT ?",
+            COLUMNS, filter
+        ))
+        .bind(&params)?
+        .all()
+        .await?
+        .results::<Video>()?;
+
+    Ok(paginate(videos, query.limit))
+}
+
+/// Returns one page of indexed videos, newest first, that still have hashes
+/// older than `versions` or frame hashes of another algorithm.
+pub async fn outdated(
+    db: &D1Database,
+    versions: &FingerprintVersions,
+    cursor: Option<&Cursor>,
+    limit: usize,
+) -> Result<(Vec<Video>, Option<String>)> {
+    let mut params: Vec<wasm_bindgen::JsValue> = vec![
+        VideoStatus::Active.as_str().into(),
+        VideoStatus::NeedsReview.as_str().into(),
+        versions.visual.into(),
+        versions.hash_algorithm.as_str().into(),
+        versions.audio.into(),
+    ];
+    let mut after = String::new();
+    if let Some(cursor) = cursor {
+        let (clause, values) = cursor.after();
+        after = format!("AND {}", clause);
+        params.extend(values);
+    }
+    params.push(((limit + 1) as i32).into());
+
+    let videos = db
+        .prepare(format!(
+            "SELECT {} FROM videos v WHERE status IN (?, ?) \
+             AND (EXISTS (SELECT 1 FROM video_hashes h WHERE h.video_id = v.id \
+             AND (h.fingerprint_version < ? OR h.hash_algorithm != ?)) \
+             OR EXISTS (SELECT 1 FROM audio_hashes a WHERE a.video_id = v.id AND a.fingerprint_version < ?)) \
+             {} ORDER BY created_at DESC, id DESC LIMIT ?",
+            COLUMNS, after
+        ))
+        .bind(&params)?
+        .all()
+        .await?
+        .results::<Video>()?;
+
+    Ok(paginate(videos, limit))
+}
+
+/// Trims a page fetched with `limit + 1` rows and builds the cursor for the
+/// next one when the extra row shows there is more.
+fn paginate(mut videos: Vec<Video>, limit: usize) -> (Vec<Video>, Option<String>) {
+    let next_cursor = if videos.len() > limit {
+        videos.truncate(limit);
+        videos.last().map(|v| {
+            Cursor {
+                created_at: v.created_at,
+                id: v.id.clone(),
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+
+    (videos, next_cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = Cursor {
+            created_at: 1706486400,
+            id: "550e8400-e29b-41d4-a716-446655440000".to_string(),
+        };
+        assert_eq!(Cursor::parse(&cursor.encode()), Some(cursor));
+        assert_eq!(Cursor::parse("not-a-cursor"), None);
+    }
+
+    #[test]
+    fn test_deleting_an_original_rematches_flagged_copies() {
+        let dependent = |status: &str| DependentRow {
+            id: format!("{}-copy", status),
+            r2_key: format!("videos/{}-copy.mp4", status),
+            status: status.to_string(),
+        };
+
+        for status in ["blocked", "needs_review"] {
+            let job = rematch(&dependent(status)).unwrap().unwrap();
+            assert_eq!(job.video_id, format!("{}-copy", status));
+            assert_eq!(job.r2_key, format!("videos/{}-copy.mp4", status));
+            assert!(!job.refingerprint);
+        }
+        for status in ["active", "processing"] {
+            assert_eq!(rematch(&dependent(status)).unwrap(), None);
+        }
+        assert!(rematch(&dependent("duplicate")).is_err());
+    }
+}